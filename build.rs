@@ -1,23 +1,364 @@
 use std::env;
-use std::path::PathBuf;
+use std::fmt::Write as _;
+use std::fs;
+use std::path::{Path, PathBuf};
 
 fn main() {
-    let _out_dir = env::var("OUT_DIR").unwrap();
+    let out_dir = env::var("OUT_DIR").unwrap();
     let workspace_dir = env::var("CARGO_MANIFEST_DIR").unwrap();
-    
+
     let database_enhanced_c: PathBuf = PathBuf::from(&workspace_dir).join("database_enhanced.c");
-    
+    let user_schema: PathBuf = PathBuf::from(&workspace_dir).join("user_schema.def");
+
     println!("cargo:rerun-if-changed={}", database_enhanced_c.display());
-        
+    println!("cargo:rerun-if-changed={}", user_schema.display());
+
+    generate_user_schema(&user_schema, Path::new(&out_dir));
+
     cc::Build::new()
         .file(&database_enhanced_c)
+        .include(&out_dir)
         .flag("-fno-stack-protector")
         .flag("-fno-delete-null-pointer-checks")
         .flag("-O0")
         .flag("-g")
         .flag("-DDEBUG")
         .compile("database_enhanced");
-    
-    
+
+
     println!("cargo:rustc-link-lib=static=database_enhanced");
 }
+
+// user_schema.def -> user_schema.h for the C backend and user_schema.rs for the Rust side,
+// see the top of user_schema.def for the format
+
+struct Const {
+    name: String,
+    value: usize,
+    ffi: bool,
+    doc: Vec<String>,
+}
+
+struct Enum {
+    name: String,
+    variants: Vec<(String, i64, Vec<String>)>,
+    ffi: bool,
+    doc: Vec<String>,
+}
+
+struct Field {
+    name: String,
+    ty: String,
+    len: Option<String>,
+    doc: Vec<String>,
+}
+
+struct Struct {
+    c_name: String,
+    rust_name: String,
+    derives: Vec<String>,
+    fields: Vec<Field>,
+    ffi: bool,
+    doc: Vec<String>,
+}
+
+#[derive(Default)]
+struct Schema {
+    consts: Vec<Const>,
+    enums: Vec<Enum>,
+    structs: Vec<Struct>,
+}
+
+fn generate_user_schema(path: &Path, out_dir: &Path) {
+    let source = fs::read_to_string(path).unwrap_or_else(|e| panic!("{}: {}", path.display(), e));
+    let schema = parse_schema(&source).unwrap_or_else(|e| panic!("{}:{}", path.display(), e));
+    let pointer_width: usize = env::var("CARGO_CFG_TARGET_POINTER_WIDTH").unwrap().parse().unwrap();
+    let layouts: Vec<Layout> = schema.structs.iter().map(|s| layout_of(&schema, s, pointer_width / 8)).collect();
+    fs::write(out_dir.join("user_schema.h"), c_header(&schema, &layouts)).unwrap();
+    fs::write(out_dir.join("user_schema.rs"), rust_items(&schema, &layouts)).unwrap();
+}
+
+// "text  # comment" -> ("text", Some("comment"))
+fn split_comment(line: &str) -> (&str, Option<String>) {
+    match line.split_once('#') {
+        Some((text, comment)) => (text.trim(), Some(comment.trim().to_string())),
+        None => (line.trim(), None),
+    }
+}
+
+fn parse_schema(source: &str) -> Result<Schema, String> {
+    let mut schema = Schema::default();
+    // comment lines directly above an item or field document it
+    let mut doc: Vec<String> = Vec::new();
+    let mut open_enum: Option<Enum> = None;
+    let mut open_struct: Option<Struct> = None;
+    for (number, line) in source.lines().enumerate() {
+        let err = |message: &str| format!("{}: {}", number + 1, message);
+        let (text, comment) = split_comment(line);
+        if text.is_empty() {
+            match comment {
+                Some(comment) => doc.push(comment),
+                None => doc.clear(),
+            }
+            continue;
+        }
+        let mut item_doc = std::mem::take(&mut doc);
+        item_doc.extend(comment);
+        let words: Vec<&str> = text.split_whitespace().collect();
+
+        if let Some(item) = open_enum.as_mut() {
+            match words.as_slice() {
+                ["end"] => schema.enums.push(open_enum.take().unwrap()),
+                [name, value] => {
+                    let value = value.parse().map_err(|_| err("enum value is not a number"))?;
+                    item.variants.push((name.to_string(), value, item_doc));
+                }
+                _ => return Err(err("expected `VARIANT VALUE` or `end`")),
+            }
+            continue;
+        }
+        if let Some(item) = open_struct.as_mut() {
+            match words.as_slice() {
+                ["end"] => schema.structs.push(open_struct.take().unwrap()),
+                [name, ty, len @ ..] if len.len() <= 1 => item.fields.push(Field {
+                    name: name.to_string(),
+                    ty: ty.to_string(),
+                    len: len.first().map(|len| len.to_string()),
+                    doc: item_doc,
+                }),
+                _ => return Err(err("expected `field TYPE [LEN]` or `end`")),
+            }
+            continue;
+        }
+
+        let (ffi, words) = match words.split_first() {
+            Some((&"ffi", rest)) => (true, rest),
+            _ => (false, words.as_slice()),
+        };
+        match words {
+            ["const", name, value] => schema.consts.push(Const {
+                name: name.to_string(),
+                value: value.parse().map_err(|_| err("const value is not a number"))?,
+                ffi,
+                doc: item_doc,
+            }),
+            ["enum", name] => {
+                open_enum = Some(Enum { name: name.to_string(), variants: Vec::new(), ffi, doc: item_doc })
+            }
+            ["struct", c_name, rust_name, derives @ ..] => {
+                open_struct = Some(Struct {
+                    c_name: c_name.to_string(),
+                    rust_name: rust_name.to_string(),
+                    derives: derives.iter().map(|d| d.to_string()).collect(),
+                    fields: Vec::new(),
+                    ffi,
+                    doc: item_doc,
+                })
+            }
+            _ => return Err(err("expected const, enum or struct")),
+        }
+    }
+    if open_enum.is_some() || open_struct.is_some() {
+        return Err("end of file: missing `end`".to_string());
+    }
+    Ok(schema)
+}
+
+// offsets as a C compiler lays the struct out, both sides are checked against these
+struct Layout {
+    size: usize,
+    offsets: Vec<usize>,
+}
+
+// (size, align) of one element of `ty`
+fn scalar(schema: &Schema, ty: &str, pointer: usize) -> (usize, usize) {
+    match ty {
+        "u8" | "char" => (1, 1),
+        "int" => (4, 4),
+        _ if ty.ends_with('*') => (pointer, pointer),
+        _ if schema.enums.iter().any(|e| e.name == ty) => (4, 4),
+        _ => panic!("user_schema.def: unknown type {}", ty),
+    }
+}
+
+fn array_len(schema: &Schema, len: &str) -> usize {
+    len.parse().unwrap_or_else(|_| {
+        schema
+            .consts
+            .iter()
+            .find(|c| c.name == len)
+            .unwrap_or_else(|| panic!("user_schema.def: unknown length {}", len))
+            .value
+    })
+}
+
+fn layout_of(schema: &Schema, item: &Struct, pointer: usize) -> Layout {
+    let mut offset = 0usize;
+    let mut max_align = 1;
+    let mut offsets = Vec::new();
+    for field in &item.fields {
+        let (size, align) = scalar(schema, &field.ty, pointer);
+        let count = field.len.as_deref().map_or(1, |len| array_len(schema, len));
+        offset = offset.next_multiple_of(align);
+        offsets.push(offset);
+        offset += size * count;
+        max_align = max_align.max(align);
+    }
+    Layout { size: offset.next_multiple_of(max_align), offsets }
+}
+
+fn comment(out: &mut String, indent: &str, doc: &[String]) {
+    for line in doc {
+        writeln!(out, "{}// {}", indent, line).unwrap();
+    }
+}
+
+fn c_type(schema: &Schema, ty: &str) -> String {
+    match ty {
+        "u8" => "unsigned char".to_string(),
+        "char" | "int" => ty.to_string(),
+        _ if ty.ends_with('*') => format!("{} *", ty.trim_end_matches('*')),
+        _ if schema.enums.iter().any(|e| e.name == ty) => ty.to_string(),
+        _ => panic!("user_schema.def: unknown type {}", ty),
+    }
+}
+
+fn c_header(schema: &Schema, layouts: &[Layout]) -> String {
+    let mut out = String::new();
+    out.push_str("// Generated by build.rs from user_schema.def, edit that instead\n");
+    out.push_str("#ifndef USER_SCHEMA_H\n#define USER_SCHEMA_H\n\n#include <stddef.h>\n\n");
+    for item in &schema.consts {
+        comment(&mut out, "", &item.doc);
+        writeln!(out, "#define {} {}", item.name, item.value).unwrap();
+    }
+    for item in &schema.enums {
+        out.push('\n');
+        comment(&mut out, "", &item.doc);
+        out.push_str("typedef enum {\n");
+        for (name, value, doc) in &item.variants {
+            comment(&mut out, "    ", doc);
+            writeln!(out, "    {} = {},", name, value).unwrap();
+        }
+        writeln!(out, "}} {};", item.name).unwrap();
+        writeln!(out, "_Static_assert(sizeof({}) == 4, \"{} must be int sized\");", item.name, item.name).unwrap();
+    }
+    for (item, layout) in schema.structs.iter().zip(layouts) {
+        out.push('\n');
+        comment(&mut out, "", &item.doc);
+        out.push_str("typedef struct {\n");
+        for field in &item.fields {
+            comment(&mut out, "    ", &field.doc);
+            let ty = c_type(schema, &field.ty);
+            let separator = if ty.ends_with('*') { "" } else { " " };
+            match &field.len {
+                Some(len) => writeln!(out, "    {}{}{}[{}];", ty, separator, field.name, len).unwrap(),
+                None => writeln!(out, "    {}{}{};", ty, separator, field.name).unwrap(),
+            }
+        }
+        writeln!(out, "}} {};", item.c_name).unwrap();
+        let mismatch = format!("{} does not match user_schema.def", item.c_name);
+        writeln!(out, "_Static_assert(sizeof({}) == {}, \"{}\");", item.c_name, layout.size, mismatch).unwrap();
+        for (field, offset) in item.fields.iter().zip(&layout.offsets) {
+            writeln!(
+                out,
+                "_Static_assert(offsetof({}, {}) == {}, \"{}\");",
+                item.c_name, field.name, offset, mismatch
+            )
+            .unwrap();
+        }
+    }
+    out.push_str("\n#endif\n");
+    out
+}
+
+fn rust_type(schema: &Schema, field: &Field) -> String {
+    let element = match field.ty.as_str() {
+        "u8" | "char" => "u8".to_string(),
+        "int" => "i32".to_string(),
+        ty if ty.ends_with('*') => {
+            let c_name = ty.trim_end_matches('*');
+            let target = schema
+                .structs
+                .iter()
+                .find(|s| s.c_name == c_name)
+                .unwrap_or_else(|| panic!("user_schema.def: unknown struct {}", c_name));
+            format!("*mut {}", target.rust_name)
+        }
+        ty if schema.enums.iter().any(|e| e.name == ty) => "i32".to_string(),
+        ty => panic!("user_schema.def: unknown type {}", ty),
+    };
+    match &field.len {
+        Some(len) => format!("[{}; {}]", element, len),
+        None => element,
+    }
+}
+
+// RUST_OWNED -> RustOwned
+fn camel_case(name: &str) -> String {
+    name.split('_')
+        .map(|word| {
+            let mut chars = word.chars();
+            chars.next().map_or(String::new(), |first| first.to_string() + &chars.as_str().to_lowercase())
+        })
+        .collect()
+}
+
+fn rust_items(schema: &Schema, layouts: &[Layout]) -> String {
+    // the database_fix_full binary doesn't link the C backend, so leaves the ffi items unused
+    let unused = |ffi: bool| if ffi { "#[allow(dead_code)]\n" } else { "" };
+    let mut out = String::new();
+    out.push_str("// Generated by build.rs from user_schema.def, edit that instead\n");
+    for item in &schema.consts {
+        comment(&mut out, "", &item.doc);
+        writeln!(out, "{}pub const {}: usize = {};", unused(item.ffi), item.name, item.value).unwrap();
+    }
+    for item in &schema.enums {
+        out.push('\n');
+        comment(&mut out, "", &item.doc);
+        // fields keep the raw i32, C can store anything there, TryFrom checks it
+        out.push_str(unused(item.ffi));
+        writeln!(out, "#[derive(Debug, Clone, Copy, PartialEq, Eq)]\n#[repr(i32)]\npub enum {} {{", item.name).unwrap();
+        for (name, value, doc) in &item.variants {
+            comment(&mut out, "    ", doc);
+            writeln!(out, "    {} = {},", camel_case(name), value).unwrap();
+        }
+        out.push_str("}\n");
+        writeln!(out, "impl TryFrom<i32> for {} {{\n    type Error = i32;\n", item.name).unwrap();
+        writeln!(out, "    fn try_from(value: i32) -> Result<Self, i32> {{\n        match value {{").unwrap();
+        for (name, value, _) in &item.variants {
+            writeln!(out, "            {} => Ok({}::{}),", value, item.name, camel_case(name)).unwrap();
+        }
+        out.push_str("            _ => Err(value),\n        }\n    }\n}\n");
+    }
+    for (item, layout) in schema.structs.iter().zip(layouts) {
+        out.push('\n');
+        comment(&mut out, "", &item.doc);
+        writeln!(out, "// {} in C", item.c_name).unwrap();
+        out.push_str(unused(item.ffi));
+        if !item.derives.is_empty() {
+            writeln!(out, "#[derive({})]", item.derives.join(", ")).unwrap();
+        }
+        writeln!(out, "#[repr(C)]\npub struct {} {{", item.rust_name).unwrap();
+        for field in &item.fields {
+            comment(&mut out, "    ", &field.doc);
+            writeln!(out, "    pub {}: {},", field.name, rust_type(schema, field)).unwrap();
+        }
+        out.push_str("}\n");
+        let mismatch = format!("{} does not match user_schema.def", item.rust_name);
+        writeln!(
+            out,
+            "const _: () = assert!(std::mem::size_of::<{}>() == {}, \"{}\");",
+            item.rust_name, layout.size, mismatch
+        )
+        .unwrap();
+        for (field, offset) in item.fields.iter().zip(&layout.offsets) {
+            writeln!(
+                out,
+                "const _: () = assert!(std::mem::offset_of!({}, {}) == {}, \"{}\");",
+                item.rust_name, field.name, offset, mismatch
+            )
+            .unwrap();
+        }
+    }
+    out
+}
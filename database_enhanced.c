@@ -1,26 +1,14 @@
 #include <stdio.h>
 #include <stdlib.h>
 #include <string.h>
-#include <time.h>
 
 // Read Only - Do not modify
-#define MAX_USERS 100
-#define MAX_NAME_LEN 50
 #define INACTIVITY_THRESHOLD 5
-#define MAX_EMAIL_LEN 50
 #define MAX_PASSWORD_LENGTH 100
-#define SESSION_MAX_IDLE_TIME 1
-#define MAX_SESSIONS 100
-#define MAX_SESSION_TOKEN_LEN 32
 
-
-
-typedef enum {
-    RUST_OWNED = 0, // rust created and only rust can poitn
-    C_OWNED = 1, // c created and only c can point
-    SHARED_RUST_PRIMARY = 2, // both point but rust created, rust dealloc
-    SHARED_C_PRIMARY = 3 // both point but C created, c cdealloc
-}  OwnershipType;
+// UserStruct_t, UserDatabase_t, OwnershipType and the limits they are sized by come from
+// user_schema.def, build.rs generates this header and the matching Rust structs from it
+#include "user_schema.h"
 
 // typedef struct {
 //     int success;
@@ -30,38 +18,7 @@ typedef enum {
 //     char error_message[256];
 // } DatabaseOperationResult;
 
-
-typedef struct {
-    char password[MAX_PASSWORD_LENGTH];
-    char username[MAX_NAME_LEN];
-    int user_id;
-    char email[MAX_EMAIL_LEN];
-    int inactivity_count;
-    int is_active;
-    char session_token[MAX_SESSION_TOKEN_LEN];
-    OwnershipType ownership;
-    int ref_count;
-} UserStruct_t;
-
-typedef struct {
-    UserStruct_t *users[MAX_USERS];
-    int count;
-    int capacity;
-} UserDatabase_t;
-
-typedef struct {
-    int user_id;
-    char username[MAX_NAME_LEN];
-    char session_token[MAX_SESSION_TOKEN_LEN];
-    int session_idle_time;
-    int is_active;
-} SessionInfo_t;
-
-typedef struct {
-    SessionInfo_t *sessions[MAX_SESSIONS];
-    int session_count;
-    UserDatabase_t* db_ref;
-} SessionManager_t;
+// Sessions are kept on the Rust side (session.rs), users only carry their current token
 
 // request from c = 1 if c request to dealloc
 int can_deallocate_user(UserStruct_t* user, int requesting_from_c) {
@@ -94,13 +51,18 @@ void decrement_ref_count(UserStruct_t* user) {
 }
 
 
+int is_valid_user_pointer(UserStruct_t* user);
+
 // Global state for cross-language interaction
-static SessionManager_t* global_session_manager = NULL;
 static UserDatabase_t* global_db = NULL;
-int *global_day_counter;
+
+// The day comes from the Rust Clock, C calls back into it through this
+typedef int (*clock_day_fn)(const void* ctx);
+static const void* clock_ctx = NULL;
+static clock_day_fn clock_day = NULL;
 
 // Core database functions
-UserDatabase_t* init_database(int* dc) {
+UserDatabase_t* init_database() {
     // UserDatabase_t* db = malloc(sizeof(UserDatabase_t));
     // db->count = 0;
     // db->capacity = MAX_USERS;
@@ -119,31 +81,32 @@ UserDatabase_t* init_database(int* dc) {
     }
     
     global_db = db;
-    global_day_counter = (int*)dc;
     return global_db;
 }
 
-void add_user(UserDatabase_t* db, UserStruct_t* user) {
+// returns 0 on success, -1 if the user was not added (caller still owns it)
+int add_user(UserDatabase_t* db, UserStruct_t* user) {
     if (!db || !user) {
         printf("[C DEBUG] add_user: NULL parameters\n");
-        return;
+        return -1;
     }
     
     if (db->capacity != MAX_USERS) {
         printf("[C DEBUG] ERROR: Database corrupted! capacity=%d, expected=%d\n", 
                db->capacity, MAX_USERS);
-        return;
+        return -1;
     }
     
     if (db->count >= db->capacity) {
         printf("[C DEBUG] ERROR: Database full! count=%d\n", db->count);
-        return;
+        return -1;
     }
     #ifdef DEBUG_EN
     printf("[C-Code] Adding user: %s\n increasing count to %d\n", user->username, db->count + 1);
     #endif
     user->user_id = db->count + 1;
     db->users[db->count++] = user;
+    return 0;
 }
 
 void free_user(UserStruct_t* user) {
@@ -292,24 +255,27 @@ void copy_string(char* dest, char* src, size_t n) {
     strncpy(dest, src, n - 1);
     dest[n - 1] = '\0';
 }
-int get_current_time() {
-    return time(NULL);
+void set_clock(const void* ctx, clock_day_fn day) {
+    clock_ctx = ctx;
+    clock_day = day;
 }
 
-UserStruct_t* create_user(char* username, char* email, int user_id, char* password) {
+int get_current_day() {
+    return clock_day ? clock_day(clock_ctx) : 0;
+}
+
+UserStruct_t* create_user(char* username, char* email, int user_id, unsigned char* password_hash, unsigned char* password_salt) {
     printf("[C DEBUG] create_user called\n");
     // printf("[C DEBUG] username ptr=%p\n", (void*)username);
     // printf("[C DEBUG] email ptr=%p\n", (void*)email);
-    // printf("[C DEBUG] password ptr=%p\n", (void*)password);
     
-    if (!username || !email || !password) {
+    if (!username || !email || !password_hash || !password_salt) {
         printf("[C DEBUG] ERROR: NULL parameter detected\n");
         return NULL;
     }
     
     // printf("[C DEBUG] username='%s' (len=%zu)\n", username, strlen(username));
     // printf("[C DEBUG] email='%s' (len=%zu)\n", email, strlen(email));
-    // printf("[C DEBUG] password='%s' (len=%zu)\n", password, strlen(password));
 
     UserStruct_t* user = malloc(sizeof(UserStruct_t));
     if (!user) return NULL;
@@ -319,11 +285,11 @@ UserStruct_t* create_user(char* username, char* email, int user_id, char* passwo
     // printf("[C DEBUG] About to copy strings\n");
     copy_string(user->username, username, MAX_NAME_LEN);
     copy_string(user->email, email, MAX_EMAIL_LEN);
-    copy_string(user->password, password, MAX_PASSWORD_LENGTH);
+    memcpy(user->password_hash, password_hash, PASSWORD_HASH_LEN);
+    memcpy(user->password_salt, password_salt, PASSWORD_SALT_LEN);
     
     // printf("[C DEBUG] After copying - username='%s'\n", user->username);
     // printf("[C DEBUG] After copying - email='%s'\n", user->email);
-    // printf("[C DEBUG] After copying - password='%s'\n", user->password);
     
     user->user_id = user_id;
     user->inactivity_count = 0;
@@ -333,10 +299,6 @@ UserStruct_t* create_user(char* username, char* email, int user_id, char* passwo
     
     return user;
 }
-void update_day_counter(int *day_counter) {
-    global_day_counter = day_counter;
-}
-
 UserStruct_t* find_user_by_id(UserDatabase_t* db, int user_id) {
     if (!db) {
         return NULL;
@@ -348,76 +310,6 @@ UserStruct_t* find_user_by_id(UserDatabase_t* db, int user_id) {
     }
     return NULL;
 }
-int init_session_manager() {
-    if (global_session_manager != NULL) {
-        return 0;
-    }
-    
-    global_session_manager = malloc(sizeof(SessionManager_t));
-    if (!global_session_manager) {
-        return -1;
-    }
-    
-    global_session_manager->session_count = 0;
-    global_session_manager->db_ref = global_db;
-    // memset(global_session_manager->sessions, 0, sizeof(SessionInfo_t) * MAX_SESSIONS);
-    for (int i = 0; i < MAX_SESSIONS; i++) {
-        global_session_manager->sessions[i] = NULL;
-    }
-    #ifdef DEBUG_EN
-    printf("[C-Code] Session manager initialized\n");
-    #endif
-    return 0;
-}
-
-void generate_token(char *token,char *name, int timestamp){
-    char temp[MAX_SESSION_TOKEN_LEN];
-    snprintf(temp, sizeof(temp), "session_%s_%d", name, timestamp);
-    copy_string(token, temp, MAX_SESSION_TOKEN_LEN);
-
-  
-}
-
-char* create_user_session(UserStruct_t *user) {
-    if (!global_session_manager) {
-        if(init_session_manager()){
-            return NULL;
-        }
-    }
-
-    if (!user) {
-        return NULL;
-    }
-    if (strlen(user->username) == 0) {
-        printf("[C DEBUG] ERROR: User has empty username!\n");
-        return NULL;
-    }
-
-    if (global_session_manager->session_count >= MAX_SESSIONS) {
-        #ifdef DEBUG_EN
-        printf("[C-Code] Too many active sessions\n");
-        #endif
-        exit(1);
-    }
-    
-    char* token = malloc(MAX_SESSION_TOKEN_LEN);
-    generate_token(token, user->username, get_current_time());
-
-
-    SessionInfo_t* session = malloc(sizeof(SessionInfo_t));
-    session->user_id = user->user_id;
-    copy_string(session->username, user->username, MAX_NAME_LEN);
-    copy_string(session->session_token, token, MAX_SESSION_TOKEN_LEN);
-    session->is_active = 1;
-    session->session_idle_time = 0;
-    global_session_manager->sessions[global_session_manager->session_count] = session;
-    global_session_manager->session_count++;
-
-    #ifdef DEBUG_EN
-    printf("Created session for user %d: %s\n", user_id, token);
-    #endif
-    return token;
-}
 
 // Memory management and optimization functions
 int get_non_null_ref_count(UserDatabase_t* db) {
@@ -461,12 +353,11 @@ UserStruct_t** get_user_reference_for_debugging(UserDatabase_t* db) {
     return user_array;
 }
 
-
-
 void clone_user(UserStruct_t* src, UserStruct_t* dest) {
     copy_string(dest->username, src->username, MAX_NAME_LEN);
     copy_string(dest->email, src->email, MAX_EMAIL_LEN);
-    copy_string(dest->password, src->password,MAX_PASSWORD_LENGTH);
+    memcpy(dest->password_hash, src->password_hash, PASSWORD_HASH_LEN);
+    memcpy(dest->password_salt, src->password_salt, PASSWORD_SALT_LEN);
     dest->inactivity_count = src->inactivity_count;
     copy_string(dest->session_token, src->session_token, MAX_SESSION_TOKEN_LEN);
     dest->is_active = src->is_active;
@@ -503,13 +394,9 @@ void memory_pressure_cleanup(UserDatabase_t* db) {
         if (db->users[i] == NULL) {
             for (int j = i + 1; j < db->count; j++) {
                 if (db->users[j] != NULL) {
-                    // allocate memory before cloning, make as truct
-                    db->users[i] = malloc(sizeof(UserStruct_t));
-                    if (db->users[i] != NULL) {  
-                        clone_user(db->users[j], db->users[i]);
-                        free_user(db->users[j]);
-                        db->users[j] = NULL;  
-                    }
+                    // move the handle instead of clone + free, shared users belong to Rust
+                    db->users[i] = db->users[j];
+                    db->users[j] = NULL;
                     break;
                 }
             }
@@ -521,37 +408,9 @@ void memory_pressure_cleanup(UserDatabase_t* db) {
     #endif
 }
 
-
-
-
-SessionInfo_t* find_session_by_token(SessionManager_t* sm, char* token) {
-    for (int i = 0; i < sm->session_count; i++) {
-        if (sm->sessions[i]->is_active && strcmp(sm->sessions[i]->session_token, token) == 0) {
-            return sm->sessions[i];
-        }
-    }
-    return NULL;
-}
-
-
-int validate_user_session(char* token) {
-    
-    if (!global_session_manager || !token) {
-        return 0;
-    }
-
-    SessionInfo_t *session = find_session_by_token(global_session_manager, token);
-    if (!session) {
-        return 0;
-    }
-    if (session->session_idle_time > SESSION_MAX_IDLE_TIME) {
-
-        session->is_active = 0;
-        // free(session); // dont free let deact_usersers handle MAYBENOT
-        return 1;
-    }
-    else session->session_idle_time += 1;
-    return 0;
+int same_credentials(UserStruct_t* a, UserStruct_t* b) {
+    return memcmp(a->password_hash, b->password_hash, PASSWORD_HASH_LEN) == 0
+        && memcmp(a->password_salt, b->password_salt, PASSWORD_SALT_LEN) == 0;
 }
 
 void merge_duplicate_handles(UserDatabase_t *db){
@@ -562,16 +421,49 @@ void merge_duplicate_handles(UserDatabase_t *db){
         if (!db->users[i]) continue;
         for(int j = 0; j < i; j++){
             if (!db->users[j]) continue;
-            if(strcmp(db->users[i]->username, db->users[j]->username) == 0 && strcmp(db->users[i]->email, db->users[j]->email) == 0 && strcmp(db->users[i]->password, db->users[j]->password) == 0){
+            if(strcmp(db->users[i]->username, db->users[j]->username) == 0 && strcmp(db->users[i]->email, db->users[j]->email) == 0 && same_credentials(db->users[i], db->users[j])){
                 #ifdef DEBUG_EN
                 printf("[C-Code] Merging duplicate user handles for %s\n", db->users[i]->username);
                 #endif
-                free_user(db->users[j]);
+                // the same shared Rust user can be listed twice after repeated joins
+                UserStruct_t* duplicate = db->users[j];
                 db->users[j] = NULL;
+                free_user_safe(duplicate, 1);
             }
         }
     }
 }
+
+// Rust is about to free a user it purged, drop every C handle to it first
+void release_rust_user(UserDatabase_t* db, UserStruct_t* user) {
+    if (!db || !user) {
+        return;
+    }
+    for (int i = 0; i < db->count; i++) {
+        if (db->users[i] == user) {
+            db->users[i] = NULL;
+            decrement_ref_count(user);
+        }
+    }
+}
+
+// frees the table and drops C's reference to every user it is responsible for, a user Rust
+// still holds a reference to is left for Rust's last one. Users shared by Rust stay Rust's.
+void free_database(UserDatabase_t* db) {
+    if (!db) {
+        return;
+    }
+    for (int i = 0; i < db->count; i++) {
+        UserStruct_t* user = db->users[i];
+        if (user && (user->ownership == C_OWNED || user->ownership == SHARED_C_PRIMARY)) {
+            free_user_safe(user, 1);
+        }
+    }
+    if (global_db == db) {
+        global_db = NULL;
+    }
+    free(db);
+}
 //#REMOVE debugging function
 int is_valid_user_pointer(UserStruct_t* user) {
     if (!user) return 0;
@@ -652,11 +544,11 @@ void update_database_daily(UserDatabase_t* db) {
     }
     printf("[C DEBUG] update_database_daily completed\n");
 
-    if(*global_day_counter % 4 == 0){
+    if(get_current_day() % 4 == 0){
         merge_duplicate_handles(db);
     }
 
-    if (*global_day_counter % 8 == 0){
+    if (get_current_day() % 8 == 0){
         memory_pressure_cleanup(db);
     }
 }
@@ -671,35 +563,42 @@ UserStruct_t* find_user_by_username(UserDatabase_t* db, char* user_name) {
     return NULL;
 }
 
-
-char* user_login(UserDatabase_t* db, char* user_name) {
-    UserStruct_t* user = find_user_by_username(db, user_name);
-    if (!db || !user_name) {
-        return NULL;
+UserStruct_t* find_user_by_email(UserDatabase_t* db, char* email) {
+    for (int i = 0; i < db->count; i++) {
+        if (!db->users[i]) continue;
+        if (strcmp(db->users[i]->email, email) == 0) {
+            return db->users[i];
+        }
     }
-    #ifdef DEBUG_EN
-    printf("[C-Code] User[%d] %s logged in after %d days\n", user->user_id, user->username, user->inactivity_count);
-    #endif
-    user->inactivity_count = 0;
-    char *token = create_user_session(user);
-    copy_string(user->session_token, token, MAX_SESSION_TOKEN_LEN);
-    user->is_active = 1;
-    return token;
+    return NULL;
 }
 
-char* get_password(UserDatabase_t* db, char* username) {
-    if (!db || !username) return NULL;
+// the salt is not secret, Rust needs it to derive the candidate hash for verify_credentials
+int get_password_salt(UserDatabase_t* db, char* username, unsigned char* salt_out) {
+    if (!db || !username || !salt_out) return -1;
 
     UserStruct_t* user = find_user_by_username(db, username);
-    if (!user) return NULL;
-    #ifdef DEBUG_EN
-        printf("Password  Request for User[%d] %s is %s\n", user->user_id, user->username, user->password);
-    #endif
-    return user->password;
+    if (!user) return -1;
+    memcpy(salt_out, user->password_salt, PASSWORD_SALT_LEN);
+    return 0;
 }
 
+// 1 if candidate_hash matches the stored hash, 0 if not, -1 if there is no such user
+// the stored hash never leaves C, and the compare does not stop at the first mismatch
+int verify_credentials(UserDatabase_t* db, char* username, unsigned char* candidate_hash) {
+    if (!db || !username || !candidate_hash) return -1;
 
-
+    UserStruct_t* user = find_user_by_username(db, username);
+    if (!user) return -1;
+    unsigned char diff = 0;
+    for (int i = 0; i < PASSWORD_HASH_LEN; i++) {
+        diff |= user->password_hash[i] ^ candidate_hash[i];
+    }
+    #ifdef DEBUG_EN
+        printf("Credential check for User[%d] %s: %s\n", user->user_id, user->username, diff == 0 ? "ok" : "mismatch");
+    #endif
+    return diff == 0;
+}
 
 UserStruct_t* find_user_by_session_token(UserDatabase_t* db, char* session_token) {
     for (int i = 0; i < db->count; i++) {
@@ -710,32 +609,3 @@ UserStruct_t* find_user_by_session_token(UserDatabase_t* db, char* session_token
     return NULL;
 }
 
-
-void deactivate_users(UserDatabase_t* rust_db) {
-    if (!global_session_manager || !rust_db) {
-        printf("[C DEBUG] deactivate_users: NULL parameters\n");
-        return;
-    }
-    for (int i = 0; i < global_session_manager->session_count; i++) {
-        if (!global_session_manager->sessions[i]) continue;
-        if (global_session_manager->sessions[i]->session_idle_time > SESSION_MAX_IDLE_TIME) {
-            global_session_manager->sessions[i]->is_active = 0;
-        }
-        
-        UserStruct_t *user = find_user_by_session_token(global_db, global_session_manager->sessions[i]->session_token);
-        if (user) {
-            user->is_active = 0;
-        }
-
-        
-        user = find_user_by_session_token(rust_db, global_session_manager->sessions[i]->session_token);
-        if (user) {
-            user->is_active = 0;
-        }
-
-        
-        free(global_session_manager->sessions[i]);
-        global_session_manager->sessions[i] = NULL;
-    }
-}
-
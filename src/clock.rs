@@ -0,0 +1,38 @@
+// The one source of day numbers for both backends.
+use std::cell::RefCell;
+use std::rc::Rc;
+
+pub trait Clock {
+    // simulation day, it only moves when increase_day advances it
+    fn day(&self) -> i32;
+    fn set_day(&mut self, day: i32);
+
+    fn advance_day(&mut self) {
+        self.set_day(self.day() + 1);
+    }
+}
+
+// C holds a pointer to the cell, the Rc keeps it alive and in place
+pub type ClockCell = RefCell<Box<dyn Clock>>;
+pub type SharedClock = Rc<ClockCell>;
+
+pub fn shared(clock: Box<dyn Clock>) -> SharedClock {
+    Rc::new(RefCell::new(clock))
+}
+
+/// Days counted from 0, they only move when told to. Deterministic, so tests and replays
+/// see the same days the original run did.
+#[derive(Default)]
+pub struct ManualClock {
+    day: i32,
+}
+
+impl Clock for ManualClock {
+    fn day(&self) -> i32 {
+        self.day
+    }
+
+    fn set_day(&mut self, day: i32) {
+        self.day = day;
+    }
+}
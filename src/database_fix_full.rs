@@ -1,47 +1,129 @@
-const MAX_USERS: usize = 100;
-const MAX_NAME_LEN: usize = 50;
-const MAX_EMAIL_LEN: usize = 50;
+use std::collections::HashMap;
+use std::fmt;
+
+use sha2::Sha256;
+
+// UserStruct, which C sees as UserStruct_t, and the limits it is sized by
+include!(concat!(env!("OUT_DIR"), "/user_schema.rs"));
+
 const MAX_PASSWORD_LENGTH: usize = 1000;
 const INACTIVITY_THRESHOLD: i32 = 5;
-const MAX_SESSION_TOKEN_LEN: usize = 32;
-
-#[derive(Debug, Clone)]
-#[repr(C)]
-pub struct UserStruct {
-    pub password: [u8; MAX_PASSWORD_LENGTH],
-    pub username: [u8; MAX_NAME_LEN],
-    pub user_id: i32,
-    pub email: [u8; MAX_EMAIL_LEN],
-    pub inactivity_count: i32,
-    pub is_active: i32,
-    pub session_token: [u8; MAX_SESSION_TOKEN_LEN],
-}
+// PBKDF2-HMAC-SHA256 rounds, deliberately slow
+const PASSWORD_HASH_ROUNDS: u32 = 100_000;
 
 impl Default for UserStruct {
     fn default() -> Self {
         UserStruct {
-            password: [0; MAX_PASSWORD_LENGTH],
+            password_hash: [0; PASSWORD_HASH_LEN],
+            password_salt: [0; PASSWORD_SALT_LEN],
             user_id: 0,
             email: [0; MAX_EMAIL_LEN],
             inactivity_count: 0,
             username: [0; MAX_NAME_LEN],
             session_token: [0; MAX_SESSION_TOKEN_LEN],
             is_active: 0,
+            ownership: OwnershipType::RustOwned as i32,
+            ref_count: 1,
+        }
+    }
+}
+
+// shared by both backends and EnhancedStudentDatabase so callers can match on failures
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum DatabaseError {
+    // no room left for another user or session
+    Full,
+    // field is "username" or "email"
+    Duplicate { field: &'static str, value: String },
+    NotFound(String),
+    InvalidInput(String),
+    IncorrectPassword,
+    // too many wrong passwords, no login until that day
+    AccountLocked { until_day: i32 },
+    BackendFailure(String),
+    // a hand-off across the FFI that OwnershipType doesn't allow, refused before it happened
+    OwnershipViolation(String),
+    SessionExpired,
+    Io(String),
+    CorruptSnapshot(String),
+}
+
+impl fmt::Display for DatabaseError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            DatabaseError::Full => write!(f, "Database is full"),
+            DatabaseError::Duplicate { field, value } => write!(f, "{} {} is already taken", field, value),
+            DatabaseError::NotFound(what) => write!(f, "{} not found", what),
+            DatabaseError::InvalidInput(why) => write!(f, "Invalid input: {}", why),
+            DatabaseError::IncorrectPassword => write!(f, "Incorrect password"),
+            DatabaseError::AccountLocked { until_day } => write!(f, "Account locked until day {}", until_day),
+            DatabaseError::BackendFailure(why) => write!(f, "C backend failure: {}", why),
+            DatabaseError::OwnershipViolation(why) => write!(f, "Ownership violation: {}", why),
+            DatabaseError::SessionExpired => write!(f, "Session expired"),
+            DatabaseError::Io(why) => write!(f, "I/O error: {}", why),
+            DatabaseError::CorruptSnapshot(why) => write!(f, "Corrupt snapshot: {}", why),
         }
     }
 }
 
+impl std::error::Error for DatabaseError {}
+
+impl From<std::io::Error> for DatabaseError {
+    fn from(err: std::io::Error) -> Self {
+        DatabaseError::Io(err.to_string())
+    }
+}
+
 #[derive(Debug)]
 pub struct UserDatabase {
-    // pub users: Vec<Option<Box<UserStruct>>>, 
-    pub users: [Option<Box<UserStruct>>; MAX_USERS],
+    // slots are never shifted, a purged user leaves a None that add_user can reuse
+    users: Vec<Option<Box<UserStruct>>>,
+    free_slots: Vec<usize>,
+    // username -> slot, kept in step by add_user, remove_user and rename_user
+    index: HashMap<String, usize>,
+    // number of live users, users.len() is the high-water mark
     pub count: i32,
-    pub capacity: i32,
+    next_user_id: i32,
+}
+
+impl UserDatabase {
+    pub fn high_water_mark(&self) -> usize {
+        self.users.len()
+    }
+
+    pub fn iter(&self) -> impl Iterator<Item = &UserStruct> {
+        self.users.iter().filter_map(|slot| slot.as_deref())
+    }
+
+    pub fn iter_mut(&mut self) -> impl Iterator<Item = &mut UserStruct> {
+        self.users.iter_mut().filter_map(|slot| slot.as_deref_mut())
+    }
+
+    pub fn first(&self) -> Option<&UserStruct> {
+        self.iter().next()
+    }
+
+    pub fn slot_of(&self, username: &str) -> Option<usize> {
+        self.index.get(username).copied()
+    }
+
+    pub fn get(&self, slot: usize) -> Option<&UserStruct> {
+        self.users.get(slot)?.as_deref()
+    }
+
+    pub fn get_mut(&mut self, slot: usize) -> Option<&mut UserStruct> {
+        self.users.get_mut(slot)?.as_deref_mut()
+    }
+
+    // the id add_user will hand out next
+    pub fn next_user_id(&self) -> i32 {
+        self.next_user_id + 1
+    }
 }
 
 // Helper fnecs
 // copy fns
-fn copy_string(dest: &mut[u8], src: &str) {
+pub fn copy_string(dest: &mut[u8], src: &str) {
     let src_bytes = src.as_bytes();
     // set a limit of the copy length, from the src, or capped at dest length -1 for null term
     let copy_length
@@ -54,7 +136,7 @@ fn copy_string(dest: &mut[u8], src: &str) {
 
 }
 // rust cant directly read byte array as a string
-fn byte_to_string(bytes: &[u8]) -> String {
+pub fn byte_to_string(bytes: &[u8]) -> String {
     let mut end = 0;
     while end < bytes.len() && bytes[end] != 0 {
         end += 1;
@@ -63,124 +145,220 @@ fn byte_to_string(bytes: &[u8]) -> String {
 }
 
 
+pub fn derive_password_hash(password: &[u8], salt: &[u8; PASSWORD_SALT_LEN]) -> [u8; PASSWORD_HASH_LEN] {
+    pbkdf2::pbkdf2_hmac_array::<Sha256, PASSWORD_HASH_LEN>(password, salt, PASSWORD_HASH_ROUNDS)
+}
+
+// fresh random salt per user, returns (salt, hash)
+pub fn hash_password(password: &[u8]) -> ([u8; PASSWORD_SALT_LEN], [u8; PASSWORD_HASH_LEN]) {
+    let mut salt = [0u8; PASSWORD_SALT_LEN];
+    getrandom::getrandom(&mut salt).expect("OS random number generator unavailable");
+    (salt, derive_password_hash(password, &salt))
+}
+
+// no early exit, so the time taken doesn't say how much of the hash matched
+fn hashes_match(a: &[u8; PASSWORD_HASH_LEN], b: &[u8; PASSWORD_HASH_LEN]) -> bool {
+    a.iter().zip(b.iter()).fold(0u8, |diff, (x, y)| diff | (x ^ y)) == 0
+}
+
 pub fn init_database() -> Box<UserDatabase> {
     let db = UserDatabase {
-        users: std::array::from_fn(|_index| None),
+        users: Vec::new(),
+        free_slots: Vec::new(),
+        index: HashMap::new(),
         count: 0,
-        capacity: MAX_USERS as i32,
+        next_user_id: 0,
     };
-    // let db = UserDatabase {
-    //     users: vec![None; MAX_USERS],  // Create Vec on heap
-    //     count: 0,
-    //     capacity: MAX_USERS as i32,
-    // };
     println!("=== RUST DEBUG: UserDatabase created, boxing it ===");
 
     Box::new(db)
 }
 
 // NOTSURE: userstruct change to mut, not sure
-pub fn add_user(db: &mut UserDatabase, mut user: Box<UserStruct>) {
-    if (db.count as usize) >= MAX_USERS {
-        return;
+// returns the slot the user was placed in, reusing purged slots before growing
+pub fn add_user(db: &mut UserDatabase, mut user: Box<UserStruct>) -> Result<usize, DatabaseError> {
+    // ids keep counting up so a reused slot never hands out an id twice
+    user.user_id = db.next_user_id + 1; // Start IDs from 1 to match expected output
+    insert_user(db, user)
+}
+
+// like add_user but keeps the user's id, for users coming back from a snapshot
+pub fn restore_user(db: &mut UserDatabase, user: Box<UserStruct>) -> Result<usize, DatabaseError> {
+    insert_user(db, user)
+}
+
+fn insert_user(db: &mut UserDatabase, user: Box<UserStruct>) -> Result<usize, DatabaseError> {
+    let username = byte_to_string(&user.username);
+    if db.index.contains_key(&username) {
+        return Err(DatabaseError::Duplicate { field: "username", value: username });
     }
-    user.user_id = db.count + 1; // Start IDs from 1 to match expected output
-    db.users[db.count as usize] = Some(user);
+    db.next_user_id = db.next_user_id.max(user.user_id);
+    let slot = match db.free_slots.pop() {
+        Some(slot) => {
+            db.users[slot] = Some(user);
+            slot
+        }
+        None => {
+            db.users.push(Some(user));
+            db.users.len() - 1
+        }
+    };
+    db.index.insert(username, slot);
     db.count += 1;
+    Ok(slot)
+}
+
+// takes the user out of its slot and puts the slot on the free list
+pub fn remove_user(db: &mut UserDatabase, slot: usize) -> Option<Box<UserStruct>> {
+    let user = db.users.get_mut(slot)?.take()?;
+    db.free_slots.push(slot);
+    db.count -= 1;
+    db.index.remove(&byte_to_string(&user.username));
+    Some(user)
 }
 
+pub fn rename_user(db: &mut UserDatabase, old_name: &str, new_name: &str) -> Result<(), DatabaseError> {
+    let slot = db
+        .slot_of(old_name)
+        .ok_or_else(|| DatabaseError::NotFound(format!("User {}", old_name)))?;
+    // compare the name as it will be stored, copy_string may cut it short
+    let mut stored = [0u8; MAX_NAME_LEN];
+    copy_string(&mut stored, new_name);
+    let stored_name = byte_to_string(&stored);
+    if matches!(db.slot_of(&stored_name), Some(other) if other != slot) {
+        return Err(DatabaseError::Duplicate { field: "username", value: stored_name });
+    }
+    if let Some(user) = db.get_mut(slot) {
+        user.username = stored;
+    }
+    db.index.remove(old_name);
+    db.index.insert(stored_name, slot);
+    Ok(())
+}
+
+// a copy of a C user keeps its C id, so ids are only unique among Rust signups
+pub fn set_user_id(db: &mut UserDatabase, username: &str, user_id: i32) -> Result<(), DatabaseError> {
+    let slot = db
+        .slot_of(username)
+        .ok_or_else(|| DatabaseError::NotFound(format!("User {}", username)))?;
+    // add_user must not hand it out again
+    db.next_user_id = db.next_user_id.max(user_id);
+    if let Some(user) = db.get_mut(slot) {
+        user.user_id = user_id;
+    }
+    Ok(())
+}
 
-pub fn create_user(username: &str, email: &str, user_id: i32, password: &str) -> Box<UserStruct> {
+pub fn create_user(
+    username: &str,
+    email: &str,
+    user_id: i32,
+    password: &str,
+) -> Result<Box<UserStruct>, DatabaseError> {
+    // same limit the plaintext field had, refused rather than cut so the stored hash is of
+    // the password the user typed
+    if password.len() >= MAX_PASSWORD_LENGTH {
+        return Err(DatabaseError::InvalidInput(format!(
+            "password must be at most {} bytes",
+            MAX_PASSWORD_LENGTH - 1
+        )));
+    }
+    let (password_salt, password_hash) = hash_password(password.as_bytes());
     let mut user = UserStruct {
-        password: [0; MAX_PASSWORD_LENGTH],
+        password_hash,
+        password_salt,
         username: [0; MAX_NAME_LEN],
         user_id,
         email: [0; MAX_EMAIL_LEN],
         inactivity_count: 0,
         is_active: 1,
         session_token: [0; MAX_SESSION_TOKEN_LEN], //init cuz cant change userstruct
+        ownership: OwnershipType::RustOwned as i32,
+        ref_count: 1,
     };
     copy_string(&mut user.email, email);
-    copy_string(&mut user.password, password);
     copy_string(&mut user.username, username);
     
-    Box::new(user)
+    Ok(Box::new(user))
 }
 
 
 // <'a> is lifetime wildcard, ties the return value lifetime to parameters (references)
 // fixes the need to return index thing
-pub fn find_user_by_username<'a>(db: & 'a UserDatabase, username: &'a str) -> Option<&'a UserStruct> {
-    for i in 0..(db.count as usize) {
-        if let Some(ref user) = db.users[i] {
-            let curr_username = byte_to_string(&user.username);
-            if curr_username == username {
-                return Some(user);
-            }
-        }
-    }
-    None
+pub fn find_user_by_username<'a>(db: &'a UserDatabase, username: &str) -> Option<&'a UserStruct> {
+    db.get(db.slot_of(username)?)
 }
 //same just add mut for ref
-pub fn find_user_by_username_mut<'a>(db: &'a mut UserDatabase, username: & 'a str) -> Option<& 'a mut UserStruct> {
-    // find 
-    let mut found_index = None;
-    for i in 0..(db.count as usize) {
-        if let Some(ref user) = db.users[i] {
-            let curr_username = byte_to_string(&user.username);
-            if curr_username == username {
-                found_index = Some(i);
-                break;
-            }
-        }
-    }
-    
-    // make it mut
-    if let Some(index) = found_index {
-        if let Some(ref mut user) = db.users[index] {
-            return Some(user);
-        }
-    }
-    
-    None
+pub fn find_user_by_username_mut<'a>(db: &'a mut UserDatabase, username: &str) -> Option<& 'a mut UserStruct> {
+    let slot = db.slot_of(username)?;
+    db.get_mut(slot)
+}
+
+// emails are not indexed, only signups with unique emails switched on look them up
+pub fn find_user_by_email<'a>(db: &'a UserDatabase, email: &str) -> Option<&'a UserStruct> {
+    db.iter().find(|user| byte_to_string(&user.email) == email)
 }
 
 pub fn print_database(db: &UserDatabase) {
-    for i in 0..(db.count as usize) {
-        if let Some(ref user) = db.users[i] {
-            let curr_username = byte_to_string(&user.username);
-            let curr_email = byte_to_string(&user.email);
-            let curr_password = byte_to_string(&user.password);
-            println!("User: {}, ID: {}, Email: {}, Inactivity: {}  Password = {}", 
-                curr_username, user.user_id, curr_email, user.inactivity_count, curr_password);
-        }
+    for user in db.iter() {
+        let curr_username = byte_to_string(&user.username);
+        let curr_email = byte_to_string(&user.email);
+        println!("User: {}, ID: {}, Email: {}, Inactivity: {}", 
+            curr_username, user.user_id, curr_email, user.inactivity_count);
     }
 }
 
-pub fn update_database_daily(db: &mut UserDatabase) {
+// both backends purge an inactive user once it is past the same threshold
+pub fn is_purgeable(user: &UserStruct) -> bool {
+    user.is_active == 0 && user.inactivity_count > INACTIVITY_THRESHOLD
+}
+
+// purged users are handed back instead of dropped, C may still hold handles to them
+// (still boxed, C matches them by address)
+#[allow(clippy::vec_box)]
+pub fn update_database_daily(db: &mut UserDatabase) -> Vec<Box<UserStruct>> {
     // TODO: Implement this function from Part 1
     println!("=== RUST DEBUG: update_database_daily started, count = {} ===", db.count);
-    if let Some(ref user) = db.users[0] {
+    if let Some(user) = db.first() {
         println!("First user before update exists: {}", byte_to_string(&user.username));
     } else {
         println!("WARNING: First user  None dbefore rust daily update!");
     }
-    for i in 0..(db.count as usize) {
-        if let Some(ref mut user) = db.users[i] {
-            if user.is_active == 0 && user.inactivity_count > INACTIVITY_THRESHOLD {
-                // user.is_active = 0;
-                db.users[i] = None;
-            } else {
-                user.inactivity_count += 1;
+    let mut purged = Vec::new();
+    for i in 0..db.high_water_mark() {
+        let purge = match db.users[i] {
+            Some(ref mut user) => {
+                if is_purgeable(user) {
+                    true
+                } else {
+                    user.inactivity_count += 1;
+                    false
+                }
             }
+            None => false,
+        };
+        if purge {
+            purged.extend(remove_user(db, i));
         }
     }
     println!("After Rust update_database_daily: count={}", db.count);
-    if let Some(ref user) = db.users[0] {
+    if let Some(user) = db.first() {
         println!("First user still exists: {}", byte_to_string(&user.username));
     } else {
         println!("WARNING: First user became None during Rust daily update!");
     }
+    purged
+}
+
+pub fn verify_credentials(db: &UserDatabase, username: &str, password: &str) -> Result<(), DatabaseError> {
+    let user = find_user_by_username(db, username)
+        .ok_or_else(|| DatabaseError::NotFound(format!("User {}", username)))?;
+    let candidate = derive_password_hash(password.as_bytes(), &user.password_salt);
+    if hashes_match(&candidate, &user.password_hash) {
+        Ok(())
+    } else {
+        Err(DatabaseError::IncorrectPassword)
+    }
 }
 
 pub fn user_login(db: &mut UserDatabase, username: &str) {
@@ -189,6 +367,160 @@ pub fn user_login(db: &mut UserDatabase, username: &str) {
     }
 }
 
+#[allow(dead_code)] // only the entry point when built as its own bin
 fn main() {
-    let mut db = init_database();
-}
\ No newline at end of file
+    let _db = init_database();
+}
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn user(username: &str, inactivity_count: i32, is_active: i32) -> Box<UserStruct> {
+        let mut user = UserStruct { inactivity_count, is_active, ..UserStruct::default() };
+        copy_string(&mut user.username, username);
+        copy_string(&mut user.email, &format!("{}@example.com", username));
+        Box::new(user)
+    }
+
+    fn names(db: &UserDatabase) -> Vec<String> {
+        db.iter().map(|user| byte_to_string(&user.username)).collect()
+    }
+
+    #[test]
+    fn removed_slot_is_reused_with_a_new_id() {
+        let mut db = init_database();
+        for name in ["alice", "bob", "carol"] {
+            add_user(&mut db, user(name, 0, 1)).unwrap();
+        }
+        let removed = remove_user(&mut db, 1).unwrap();
+        assert_eq!(byte_to_string(&removed.username), "bob");
+        assert!(remove_user(&mut db, 1).is_none());
+        assert_eq!(db.count, 2);
+
+        assert_eq!(add_user(&mut db, user("dave", 0, 1)), Ok(1));
+        assert_eq!(db.get(1).unwrap().user_id, 4);
+        assert_eq!(db.high_water_mark(), 3);
+        assert_eq!(add_user(&mut db, user("erin", 0, 1)), Ok(3));
+        assert_eq!(names(&db), ["alice", "dave", "carol", "erin"]);
+    }
+
+    #[test]
+    fn purged_slots_are_reused() {
+        let mut db = init_database();
+        add_user(&mut db, user("alice", 0, 1)).unwrap();
+        add_user(&mut db, user("bob", INACTIVITY_THRESHOLD + 1, 0)).unwrap();
+        add_user(&mut db, user("carol", 0, 0)).unwrap();
+        add_user(&mut db, user("dave", INACTIVITY_THRESHOLD + 1, 0)).unwrap();
+
+        let purged = update_database_daily(&mut db);
+        let purged: Vec<String> = purged.iter().map(|user| byte_to_string(&user.username)).collect();
+        assert_eq!(purged, ["bob", "dave"]);
+        assert_eq!(db.count, 2);
+        // survivors aged a day and kept their slots
+        assert_eq!(db.get(0).unwrap().inactivity_count, 1);
+        assert_eq!(db.get(2).unwrap().inactivity_count, 1);
+
+        let slots = [add_user(&mut db, user("erin", 0, 1)), add_user(&mut db, user("frank", 0, 1))];
+        let mut slots = slots.map(Result::unwrap);
+        slots.sort();
+        assert_eq!(slots, [1, 3]);
+        assert_eq!(db.high_water_mark(), 4);
+        assert_eq!(db.count, 4);
+    }
+
+    // every live user is found by name in the slot it occupies, and nothing else is
+    fn assert_index_matches(db: &UserDatabase) {
+        for slot in 0..db.high_water_mark() {
+            if let Some(user) = db.get(slot) {
+                assert_eq!(db.slot_of(&byte_to_string(&user.username)), Some(slot));
+            }
+        }
+        assert_eq!(db.index.len(), db.count as usize);
+    }
+
+    #[test]
+    fn index_follows_purge_and_restore() {
+        let mut db = init_database();
+        add_user(&mut db, user("alice", 0, 1)).unwrap();
+        add_user(&mut db, user("bob", INACTIVITY_THRESHOLD + 1, 0)).unwrap();
+        add_user(&mut db, user("carol", 0, 1)).unwrap();
+
+        let mut purged = update_database_daily(&mut db);
+        assert_index_matches(&db);
+        assert!(find_user_by_username(&db, "bob").is_none());
+        assert!(find_user_by_username(&db, "carol").is_some());
+
+        // back under its old id, in the slot it left
+        let bob = purged.pop().unwrap();
+        let bob_id = bob.user_id;
+        assert_eq!(restore_user(&mut db, bob), Ok(1));
+        assert_index_matches(&db);
+        assert_eq!(find_user_by_username(&db, "bob").unwrap().user_id, bob_id);
+        assert!(matches!(
+            restore_user(&mut db, user("bob", 0, 1)),
+            Err(DatabaseError::Duplicate { field: "username", .. })
+        ));
+        assert_eq!(db.count, 3);
+    }
+
+    #[test]
+    fn index_follows_renames() {
+        let mut db = init_database();
+        add_user(&mut db, user("alice", 0, 1)).unwrap();
+        add_user(&mut db, user("bob", 0, 1)).unwrap();
+
+        rename_user(&mut db, "alice", "alicia").unwrap();
+        assert_index_matches(&db);
+        assert!(find_user_by_username(&db, "alice").is_none());
+        assert_eq!(db.slot_of("alicia"), Some(0));
+        // the old name is free again
+        assert_eq!(add_user(&mut db, user("alice", 0, 1)), Ok(2));
+
+        assert!(matches!(
+            rename_user(&mut db, "alicia", "bob"),
+            Err(DatabaseError::Duplicate { field: "username", .. })
+        ));
+        assert!(matches!(rename_user(&mut db, "nobody", "carol"), Err(DatabaseError::NotFound(_))));
+        // renaming to itself is not a clash
+        rename_user(&mut db, "bob", "bob").unwrap();
+        assert_index_matches(&db);
+
+        // indexed under the name as stored, which copy_string cuts short
+        let long = "x".repeat(MAX_NAME_LEN + 5);
+        rename_user(&mut db, "bob", &long).unwrap();
+        let stored = "x".repeat(MAX_NAME_LEN - 1);
+        assert_eq!(db.slot_of(&stored), Some(1));
+        assert!(db.slot_of(&long).is_none());
+        assert!(matches!(
+            rename_user(&mut db, "alicia", &format!("{}y", long)),
+            Err(DatabaseError::Duplicate { field: "username", .. })
+        ));
+        assert_index_matches(&db);
+    }
+
+    #[test]
+    fn verify_credentials_checks_the_pbkdf2_hash() {
+        let mut db = init_database();
+        add_user(&mut db, create_user("alice", "alice@example.com", 0, "secret").unwrap()).unwrap();
+        assert_eq!(verify_credentials(&db, "alice", "secret"), Ok(()));
+        assert_eq!(verify_credentials(&db, "alice", "Secret"), Err(DatabaseError::IncorrectPassword));
+        assert_eq!(verify_credentials(&db, "alice", ""), Err(DatabaseError::IncorrectPassword));
+        assert!(matches!(verify_credentials(&db, "bob", "secret"), Err(DatabaseError::NotFound(_))));
+        let alice = find_user_by_username(&db, "alice").unwrap();
+        assert_eq!(alice.password_hash, derive_password_hash(b"secret", &alice.password_salt));
+    }
+
+    #[test]
+    fn same_password_is_salted_differently() {
+        let first = create_user("alice", "alice@example.com", 1, "secret").unwrap();
+        let second = create_user("bob", "bob@example.com", 2, "secret").unwrap();
+        assert_ne!(first.password_salt, second.password_salt);
+        assert_ne!(first.password_hash, second.password_hash);
+    }
+
+    #[test]
+    fn overlong_password_is_refused_not_cut() {
+        let long = "x".repeat(MAX_PASSWORD_LENGTH);
+        assert!(matches!(create_user("alice", "alice@example.com", 1, &long), Err(DatabaseError::InvalidInput(_))));
+    }
+}
@@ -1,48 +1,49 @@
-use std::ffi::{CStr, CString};
-use std::os::raw::{c_char, c_int};
-use crate::database_fix_full::{UserStruct,UserDatabase};
-
-
-// C struct representations
-#[repr(C)]
-pub struct UserStructT {
-    pub password: [c_char; 100],
-    pub username: [c_char; 50],
-    pub user_id: c_int,
-    pub email: [c_char; 50],
-    pub inactivity_count: c_int,
-    pub is_active: c_int,
-    pub session_token: [c_char; 32],
-    pub ownership: c_int,        
-    pub ref_count: c_int,        
-}
+use std::ffi::CString;
+use std::os::raw::{c_char, c_int, c_void};
+use std::rc::{Rc, Weak};
+use std::collections::HashSet;
+use crate::clock::{ClockCell, SharedClock};
+use crate::database_fix_full::{
+    byte_to_string, copy_string, derive_password_hash, hash_password, DatabaseError, UserDatabase,
+    OwnershipType, UserDatabaseT, UserStruct, MAX_USERS, PASSWORD_SALT_LEN,
+};
+use crate::ownership::{hand_off, Ownership, Side, Transfer};
+use crate::wal::Backend;
+use crate::session::{
+    EvictionPolicy, RefreshToken, Session, SessionLifetimes, SessionManager, SessionState, SessionStatus,
+    TokenMode, MAX_SESSIONS,
+};
 
+// size of the plaintext password field C used to have, signups must fit it with its NUL
+const C_MAX_PASSWORD_LENGTH: usize = 100;
 
 
-#[repr(C)]
-pub struct UserDatabaseT {
-    pub users: [*mut UserStructT; 100],
-    pub count: c_int,
-    pub capacity: c_int,
-}
+// C's UserStruct_t is the Rust UserStruct, both are generated from user_schema.def
+pub type UserStructT = UserStruct;
 
+// not every C entry point is wired up from Rust yet
+#[allow(dead_code)]
 extern "C" {
-    fn init_database(dc : *const i32) -> *mut UserDatabaseT;
+    fn init_database() -> *mut UserDatabaseT;
+    fn free_database(db: *mut UserDatabaseT);
+    fn set_clock(ctx: *const c_void, day: Option<extern "C" fn(*const c_void) -> c_int>);
     fn create_user(
         username: *const c_char,
         email: *const c_char,
         user_id: c_int,
-        password: *const c_char,
+        password_hash: *const u8,
+        password_salt: *const u8,
     ) -> *mut UserStructT;
-    fn add_user(db: *mut UserDatabaseT, user: *mut UserStructT);
+    fn add_user(db: *mut UserDatabaseT, user: *mut UserStructT) -> c_int;
+    fn free_user(user: *mut UserStructT);
+    fn free_user_safe(user: *mut UserStructT, requesting_from_c: c_int);
+    fn increment_ref_count(user: *mut UserStructT);
+    fn decrement_ref_count(user: *mut UserStructT);
     // fn find_user_by_id(db: *mut UserDatabaseT, user_id: c_int) -> *mut UserStructT;
 
     // for sharing
     fn add_shared_user_from_rust(db: *mut UserDatabaseT, user: *mut UserStructT);
     fn get_user_references_for_sharing(db: *mut UserDatabaseT, count: *mut c_int) -> *mut *mut UserStructT;
-    // Session management
-    pub fn create_user_session(user: *const UserStructT) -> *mut c_char;
-    fn validate_user_session(token: *const c_char) -> c_int;
 
     // Memory management and optimization
     fn get_user_reference_for_debugging(
@@ -52,60 +53,245 @@ extern "C" {
     // Additional C functions present in database_enhanced.c
     fn print_database(db: *mut UserDatabaseT);
     fn update_database_daily(db: *mut UserDatabaseT);
-    fn user_login(db: *mut UserDatabaseT, user_name: *const c_char)->*const c_char;
-    fn get_password(db: *mut UserDatabaseT, user_name: *const c_char) -> *const c_char;
+    fn get_password_salt(db: *mut UserDatabaseT, user_name: *const c_char, salt_out: *mut u8) -> c_int;
+    fn verify_credentials(db: *mut UserDatabaseT, user_name: *const c_char, candidate_hash: *const u8) -> c_int;
     fn get_non_null_ref_count(db: *mut UserDatabaseT) -> c_int;
     fn find_user_by_username( db: *mut UserDatabaseT, user_name: *const c_char) -> *mut UserStructT;
-    fn deactivate_users(db: *mut UserDatabaseT);
-    fn init_session_manager();
-    fn update_day_counter(dc : *const i32);
+    fn find_user_by_email(db: *mut UserDatabaseT, email: *const c_char) -> *mut UserStructT;
+    fn find_user_by_session_token(db: *mut UserDatabaseT, session_token: *const c_char) -> *mut UserStructT;
+    fn release_rust_user(db: *mut UserDatabaseT, user: *mut UserStructT);
+}
+
+// false for a shared Rust user and for one whose ownership C has scribbled over
+fn is_c_primary(user: &UserStructT) -> bool {
+    Ownership::of(user).is_ok_and(|ownership| ownership.primary() == Side::C)
+}
+
+// how many of the C table's slots point at `user`, repeated joins can list a Rust user twice
+unsafe fn listings(db: *const UserDatabaseT, user: *const UserStructT) -> usize {
+    let db = &*db;
+    db.users[..db.count.max(0) as usize].iter().filter(|&&listed| std::ptr::eq(listed, user)).count()
 }
 
-pub struct UserReference {
-    pub username: String,
-    pub ptr: *mut UserStructT,
+// what a C backend login does to the user
+unsafe fn mark_logged_in(user: *mut UserStructT, token: &str) {
+    (*user).inactivity_count = 0;
+    copy_string(&mut (*user).session_token, token);
+    (*user).is_active = 1;
+}
+
+// strings with an interior NUL can't cross into C
+fn to_c_string(value: &str, field: &str) -> Result<CString, DatabaseError> {
+    CString::new(value).map_err(|_| DatabaseError::InvalidInput(format!("{} contains a NUL byte", field)))
+}
+
+/// A counted reference to a user the C backend is responsible for. It holds one of the
+/// user's `ref_count`s, so C purging the user only unlists it, and the last handle dropped
+/// frees it. Reads check the user is still listed in the C table first. A handle may outlive
+/// the table, the user is then Rust's last reference.
+pub struct CUserHandle {
+    db: *mut UserDatabaseT,
+    // dead once DatabaseExtensions has freed `db`
+    table: Weak<()>,
+    ptr: *mut UserStructT,
+    // as it was when the handle was taken, for messages once the user is gone
+    username: String,
 }
 
-impl UserReference {
-    pub fn new(username: String, ptr: *mut UserStructT) -> Self {
-        UserReference {
-            username,
+impl CUserHandle {
+    // None for a null pointer or a shared Rust user, Rust frees those whatever C counts
+    unsafe fn acquire(db: *mut UserDatabaseT, table: Weak<()>, ptr: *mut UserStructT) -> Result<Option<Self>, DatabaseError> {
+        if ptr.is_null() || !is_c_primary(&*ptr) {
+            return Ok(None);
+        }
+        hand_off(ptr, &[Transfer::Share { to: Side::Rust }], || {
+            increment_ref_count(ptr);
+            // increment_ref_count only counts, mark it shared the way C does when it shares
+            (*ptr).ownership = OwnershipType::SharedCPrimary as i32;
+        })?;
+        Ok(Some(Self::adopt(db, table, ptr)))
+    }
+
+    // takes over a reference C already counted for Rust
+    unsafe fn adopt(db: *mut UserDatabaseT, table: Weak<()>, ptr: *mut UserStructT) -> Self {
+        CUserHandle {
+            db,
+            table,
             ptr,
+            username: byte_to_string(&(*ptr).username),
+        }
+    }
+
+    /// True once C has dropped the user from its table (purged, merged or failed validation)
+    pub fn is_released(&self) -> bool {
+        self.target().is_err()
+    }
+
+    pub fn username(&self) -> Result<String, DatabaseError> {
+        self.target().map(|user| byte_to_string(&user.username))
+    }
+
+    pub fn email(&self) -> Result<String, DatabaseError> {
+        self.target().map(|user| byte_to_string(&user.email))
+    }
+
+    pub fn user_id(&self) -> Result<i32, DatabaseError> {
+        self.target().map(|user| user.user_id)
+    }
+
+    pub fn inactivity_count(&self) -> Result<i32, DatabaseError> {
+        self.target().map(|user| user.inactivity_count)
+    }
+
+    /// A copy Rust owns outright, credentials, state and current token included
+    pub fn copy(&self) -> Result<UserStruct, DatabaseError> {
+        self.target().map(|user| UserStruct {
+            ownership: OwnershipType::RustOwned as i32,
+            ref_count: 1,
+            ..user.clone()
+        })
+    }
+
+    // the handle's count keeps the allocation alive, so its address can't be handed to
+    // another user and comparing it against the table is enough
+    fn is_listed(&self) -> bool {
+        self.table.strong_count() > 0 && unsafe { listings(self.db, self.ptr) > 0 }
+    }
+
+    fn target(&self) -> Result<&UserStructT, DatabaseError> {
+        if !self.is_listed() {
+            return Err(DatabaseError::NotFound(format!("User {} (released by the C backend)", self.username)));
+        }
+        Ok(unsafe { &*self.ptr })
+    }
+}
+
+impl Drop for CUserHandle {
+    fn drop(&mut self) {
+        // still listed, C keeps its own reference. Otherwise this is the last one, and
+        // free_user_safe frees the user once the count reaches zero.
+        let ptr = self.ptr;
+        let released = unsafe {
+            if self.is_listed() {
+                hand_off(ptr, &[Transfer::Release { by: Side::Rust }], || decrement_ref_count(ptr))
+            } else {
+                hand_off(ptr, &[Transfer::Free { by: Side::C }], || free_user_safe(ptr, 1))
+            }
+        };
+        // refused, the user is left as it is rather than freed under someone
+        if let Err(e) = released {
+            println!("[Ownership Error] {}", e);
         }
     }
 }
 
 pub struct DatabaseExtensions {
     db: *mut UserDatabaseT,
+    // handles to C users hold a Weak to this, it goes when `db` is freed
+    table: Rc<()>,
+    // C calls back into this, holding a reference keeps the pointer it was given valid
+    clock: SharedClock,
+    sessions: SessionManager,
+}
+
+// C keeps the clock and the table in statics, so nothing may call into it once this is gone.
+// Rust users C lists must still be alive. free_database leaves users a handle still counts
+// alone, the last handle frees them.
+impl Drop for DatabaseExtensions {
+    fn drop(&mut self) {
+        unsafe {
+            // C falls back to day 0 rather than reading a freed clock
+            set_clock(std::ptr::null(), None);
+            free_database(self.db);
+        }
+    }
+}
+
+// ctx is the SharedClock handed to set_clock
+extern "C" fn clock_day(ctx: *const c_void) -> c_int {
+    let clock = unsafe { &*(ctx as *const ClockCell) };
+    clock.borrow().day()
 }
 
 impl DatabaseExtensions {
-    pub fn new(dc : *const i32) -> Self {
+    pub fn new(clock: SharedClock) -> Self {
        println!("Initializing Enhanced Student Database System...");
         // let dc = Box::new(0);
         println!("Making C database");
         println!("=== C DEBUG 1: Starting DatabaseExtensions::new ===");
         
         println!("=== C DEBUG 2: About to call init_database ===");
-        let db = unsafe { init_database(dc) };
-        println!("=== C DEBUG 3: init_database completed ===");
-        
-        println!("=== C DEBUG 4: About to call init_session_manager ===");
+        let db = unsafe { init_database() };
         unsafe {
-            init_session_manager();
+            set_clock(Rc::as_ptr(&clock) as *const c_void, Some(clock_day));
         }
-        println!("=== C DEBUG 5: init_session_manager completed ===");
+        println!("=== C DEBUG 3: init_database completed ===");
         
         println!("=== C DEBUG 6: Creating DatabaseExtensions struct ===");
-        let result = DatabaseExtensions { db };
+        let result = DatabaseExtensions {
+            db,
+            table: Rc::new(()),
+            clock,
+            sessions: SessionManager::new(MAX_SESSIONS),
+        };
         println!("=== C DEBUG 7: DatabaseExtensions created successfully ===");
         result
     }
-    pub fn get_user_password(&self, user: *mut UserStructT) -> String {
+    // C only hands out the salt, the hash comparison happens on its side
+    pub fn verify_credentials(&self, username: &str, password: &str) -> Result<(), DatabaseError> {
+        let c_username = to_c_string(username, "username")?;
+        let mut salt = [0u8; PASSWORD_SALT_LEN];
+        if unsafe { get_password_salt(self.db, c_username.as_ptr(), salt.as_mut_ptr()) } != 0 {
+            return Err(DatabaseError::NotFound(format!("User {}", username)));
+        }
+        let candidate = derive_password_hash(password.as_bytes(), &salt);
+        match unsafe { verify_credentials(self.db, c_username.as_ptr(), candidate.as_ptr()) } {
+            1 => Ok(()),
+            0 => Err(DatabaseError::IncorrectPassword),
+            _ => Err(DatabaseError::NotFound(format!("User {}", username))),
+        }
+    }
+    /// Overwrite the user C is responsible for under the winner's name with the winner's
+    /// record, keeping the C user's id, ownership and ref count. A shared Rust user listed
+    /// under the same name is left alone.
+    pub fn replace_c_user(&self, winner: &UserStruct) -> Result<(), DatabaseError> {
+        let user = self.c_primary_user(&byte_to_string(&winner.username))?;
         unsafe {
-            let password_ptr = get_password(self.db, (*user).username.as_ptr());
-            CStr::from_ptr(password_ptr).to_string_lossy().to_string()
+            *user = UserStruct {
+                user_id: (*user).user_id,
+                ownership: (*user).ownership,
+                ref_count: (*user).ref_count,
+                ..winner.clone()
+            };
         }
+        Ok(())
+    }
+    /// Give the user C is responsible for under `username` the id `user_id`. C ids were never
+    /// unique, shared Rust users keep their Rust ids, so nothing is checked.
+    pub fn set_c_user_id(&self, username: &str, user_id: i32) -> Result<(), DatabaseError> {
+        let user = self.c_primary_user(username)?;
+        unsafe { (*user).user_id = user_id };
+        Ok(())
+    }
+    /// Whether C is responsible for a user called `username`, a Rust copy of it doesn't change that
+    pub fn holds_c_user(&self, username: &str) -> bool {
+        self.c_primary_user(username).is_ok()
+    }
+    // the C-primary user called `username`, find_user_by_username may return a shared Rust
+    // user of the same name instead
+    fn c_primary_user(&self, username: &str) -> Result<*mut UserStructT, DatabaseError> {
+        let db = unsafe { &*self.db };
+        db.users[..db.count.max(0) as usize]
+            .iter()
+            .copied()
+            .find(|&user| {
+                !user.is_null() && unsafe { is_c_primary(&*user) && byte_to_string(&(*user).username) == username }
+            })
+            .ok_or_else(|| DatabaseError::NotFound(format!("User {}", username)))
+    }
+    /// A counted handle to the C user called `username`, None if C doesn't hold one
+    pub fn c_user_handle(&self, username: &str) -> Result<Option<CUserHandle>, DatabaseError> {
+        unsafe { CUserHandle::acquire(self.db, Rc::downgrade(&self.table), self.get_user_in_c_backend(username)) }
     }
     pub fn get_user_in_c_backend(&self, username: &str) -> *mut UserStructT {
         let c_username = match CString::new(username) {
@@ -121,123 +307,147 @@ impl DatabaseExtensions {
             }
         }
     }
-    pub fn get_last_user_id(&self) -> i32 {
-        unsafe { (*self.db).count - 1 }
+    pub fn get_user_in_c_backend_by_email(&self, email: &str) -> *mut UserStructT {
+        let c_email = match CString::new(email) {
+            Ok(s) => s,
+            Err(_) => return std::ptr::null_mut(),
+        };
+        unsafe { find_user_by_email(self.db, c_email.as_ptr()) }
     }
-    pub fn sync_user_to_c_backend(
+    /// Hash and validate a C backend signup without inserting it, `restore_c_user` adds it.
+    /// The record carries the id C will hand out next so it can be logged ahead of the insert.
+    pub fn prepare_c_user(
         &self,
         username: &str,
         email: &str,
-        user_id: i32,
         password: &str,
-    ) -> Result<(), String> {
-        // println!("[RUST DEBUG] sync_user_to_c_backend called");
-        // println!("[RUST DEBUG] username: '{}' (len={})", username, username.len());
-        // println!("[RUST DEBUG] email: '{}' (len={})", email, email.len());
-        // println!("[RUST DEBUG] password: '{}' (len={})", password, password.len());
-        
-        let c_username = CString::new(username).map_err(|_| "Invalid username")?;
-        let c_email = CString::new(email).map_err(|_| "Invalid email")?;
-        let c_password = CString::new(password).map_err(|_| "Invalid password")?;
-        
-        // println!("[RUST DEBUG] CStrings created successfully");
-        
+    ) -> Result<UserStruct, DatabaseError> {
+        to_c_string(username, "username")?;
+        to_c_string(email, "email")?;
+        // the C struct held 99 bytes of plaintext, keep refusing anything it couldn't
+        if password.len() >= C_MAX_PASSWORD_LENGTH {
+            return Err(DatabaseError::InvalidInput(format!(
+                "password must be at most {} bytes",
+                C_MAX_PASSWORD_LENGTH - 1
+            )));
+        }
+        let (password_salt, password_hash) = hash_password(password.as_bytes());
+
+        let mut user = UserStruct {
+            user_id: unsafe { (*self.db).count } + 1,
+            password_hash,
+            password_salt,
+            is_active: 1,
+            ..Default::default()
+        };
+        copy_string(&mut user.username, username);
+        copy_string(&mut user.email, email);
+        Ok(user)
+    }
+    /// Lists a Rust user in the C table too, Rust stays responsible for freeing it
+    pub fn sync_user_from_rust_db(&self, user: *mut UserStructT) -> Result<(), DatabaseError> {
+        // add_shared_user_from_rust only prints when the table is full
+        if unsafe { (*self.db).count } as usize >= MAX_USERS {
+            return Err(DatabaseError::Full);
+        }
         unsafe {
-            // println!("[RUST DEBUG] About to call C create_user");
-            let user = create_user(c_username.as_ptr(), c_email.as_ptr(), user_id, c_password.as_ptr());
-            // println!("[RUST DEBUG] C create_user returned: {:p}", user);
-            
-            if user.is_null() {
-                return Err("Failed to create user".to_string());
-            }
-            
-            // println!("[RUST DEBUG] About to call C add_user");
-            add_user(self.db, user);
-            // println!("[RUST DEBUG] C add_user completed");
+            hand_off(user, &[Transfer::Share { to: Side::C }], || add_shared_user_from_rust(self.db, user))
         }
-        Ok(())
     }
-    pub fn sync_user_from_rust_db(&self,user: *mut UserStructT){
-            unsafe {
-                (*user).ownership = 0; // RUST_OWNED initially
-                (*user).ref_count = 1;
-                add_shared_user_from_rust(self.db, user);
-                // add_user(self.db, user);
-            }
+
+    /// Read-only, see SessionManager::status
+    pub fn validate_session(&self, token: &str) -> SessionStatus {
+        self.sessions.status(token, self.clock.borrow().day())
     }
 
-    pub fn cast_user_struct(user: &UserStruct) -> *const UserStructT {
-        user as *const UserStruct as *const UserStructT
+    /// The daily idle tick of every live session, see SessionManager::tick
+    pub fn tick_sessions(&mut self) {
+        let day = self.clock.borrow().day();
+        self.sessions.tick_all(day);
     }
-    pub fn create_session(&self, user: &UserStruct) -> Result<String, String> {
-        unsafe {
-            let userp = DatabaseExtensions::cast_user_struct(user);
-            let token_ptr = create_user_session(userp);
-            if token_ptr.is_null() {
-                return Err("Failed to create session".to_string());
-            }
 
-            let token = CStr::from_ptr(token_ptr).to_string_lossy().to_string();
-            Ok(token)
-        }
+    /// Sliding renewal, see SessionManager::touch
+    pub fn touch_session(&mut self, token: &str) -> SessionStatus {
+        let day = self.clock.borrow().day();
+        self.sessions.touch(token, day)
     }
 
-    pub fn validate_session(&self, token: &str) -> Result<i32, String> {
-        let c_token = CString::new(token).map_err(|_| "Invalid token")?;
+    pub fn set_session_eviction(&mut self, policy: EvictionPolicy) {
+        self.sessions.set_policy(policy);
+    }
 
-        unsafe {
-            let user_id = validate_user_session(c_token.as_ptr());
-            if user_id == 0 {
-                Err("Invalid session".to_string())
-            } else {
-                Ok(user_id)
-            }
-        }
+    pub fn set_session_lifetimes(&mut self, lifetimes: SessionLifetimes) {
+        self.sessions.set_lifetimes(lifetimes);
     }
 
-    pub fn login_user(&self, user_name: &str)-> Result<String, String>{
-        unsafe {
-            let c_user_name = CString::new(user_name).map_err(|_| "Invalid username")?;
-            let token_ptr = user_login(self.db, c_user_name.as_ptr());
-            if token_ptr.is_null() {
-                return Err("Failed to create session".to_string());
-            }
-            Ok(CStr::from_ptr(token_ptr).to_string_lossy().to_string())
-        }
+    pub fn set_token_mode(&mut self, mode: TokenMode) {
+        self.sessions.set_token_mode(mode);
     }
 
+    pub fn export_revoked_tokens(&self) -> Vec<(String, i32, SessionState)> {
+        self.sessions
+            .revoked_tokens()
+            .map(|(token, expires_day, state)| (token.clone(), expires_day, state))
+            .collect()
+    }
 
-    pub fn get_all_user_references(&self) -> Vec<Box<UserStruct>> {
-        // let refs = unsafe { get_user_reference_for_debugging(self.db)};
-        // let ref_count = unsafe { get_non_null_ref_count(self.db) };
-        let c_user_ptrs = self.get_user_references_for_sharing();
-
-        let mut user_refs = Vec::new();
-        // let refs_slice = unsafe { std::slice::from_raw_parts(refs, ref_count as usize) };
-        // for &user_ptr in refs_slice {
-        //     if !user_ptr.is_null() {
-        //         let user = unsafe { Box::from_raw(user_ptr as *mut UserStruct) };
-        //         user_refs.push(user);
-        //     }
-        // }
-        // user_refs
-        // let mut user_refs = Vec::new();
-        for ptr in c_user_ptrs {
-            if !ptr.is_null() {
-                unsafe {
-                    // DON'T use Box::from_raw - that transfers ownership
-                    // Instead, create a reference wrapper or handle this differently
-                    
-                    // For now, return empty vector to avoid double-free
-                    // The join will work but won't add C users to Rust
-                    break;
-                }
-            }
-        }
-        
-        user_refs
+    pub fn restore_revoked(&mut self, token: &str, expires_day: i32, state: SessionState) {
+        self.sessions.restore_revoked(token, expires_day, state);
+    }
+
+    pub fn generate_token(&self) -> Result<String, DatabaseError> {
+        self.sessions.generate_token()
+    }
+
+    /// A token for a session `user_id` of `backend` starts today, installed later with
+    /// `restore_login`
+    pub fn mint_session_token(&self, user_id: i32, backend: Backend) -> Result<String, DatabaseError> {
+        self.sessions.mint(user_id, backend, self.clock.borrow().day())
+    }
+
+    /// Grant a refresh token for the user and client of `session`, issued today
+    pub fn issue_refresh(&mut self, session: &Session, token: &str) {
+        let issued_day = self.clock.borrow().day();
+        self.sessions.issue_refresh(RefreshToken {
+            token: token.to_string(),
+            username: session.username.clone(),
+            client: session.client.clone(),
+            issued_day,
+        });
+    }
+
+    pub fn restore_refresh(&mut self, grant: RefreshToken) {
+        self.sessions.issue_refresh(grant);
+    }
+
+    pub fn export_refresh_tokens(&self) -> Vec<RefreshToken> {
+        self.sessions.refresh_tokens().cloned().collect()
+    }
+
+    /// The grant behind a refresh token that can still be exchanged today
+    pub fn refresh_grant(&self, token: &str) -> Result<RefreshToken, DatabaseError> {
+        self.sessions.refresh_grant(token, self.clock.borrow().day()).cloned()
+    }
+
+    pub fn take_refresh(&mut self, token: &str) -> Option<RefreshToken> {
+        self.sessions.take_refresh(token)
     }
-    pub fn increment_day(&self, rust_db: &UserDatabase) {
+
+    pub fn revoke_refresh_tokens(&mut self, ends: impl Fn(&RefreshToken) -> bool) -> usize {
+        self.sessions.revoke_refresh_where(ends)
+    }
+
+
+    /// Rust-owned copies of every user C is responsible for, C keeps its own. Box::from_raw
+    /// would hand Rust memory C still frees, so the users are copied through handles and the
+    /// references C counted for Rust are given back as those drop.
+    pub fn get_all_user_references(&self) -> Vec<UserStruct> {
+        self.get_user_references_for_sharing()
+            .iter()
+            .filter_map(|handle| handle.copy().ok())
+            .collect()
+    }
+    pub fn increment_day(&mut self, rust_db: &mut UserDatabase) {
         println!("=== C DEBUG: Starting increment_day ===");
         // println!("=== C DEBUG: Checking database pointer: {:p} ===", self.db);
         if self.db.is_null() {
@@ -251,11 +461,11 @@ impl DatabaseExtensions {
             let count = std::ptr::read_volatile(&(*self.db).count);
             // println!("=== C DEBUG: Database count: {} ===", count);
             
-            let capacity = std::ptr::read_volatile(&(*self.db).capacity);
+            let _capacity = std::ptr::read_volatile(&(*self.db).capacity);
             // println!("=== C DEBUG: Database capacity: {} ===", capacity);
             
             // Check if count is reasonable
-            if count < 0 || count > 1000 {
+            if !(0..=1000).contains(&count) {
                 println!("=== C DEBUG: ERROR - Invalid count: {} ===", count);
                 return;
             }
@@ -267,28 +477,47 @@ impl DatabaseExtensions {
             // println!("=== C DEBUG: deactivate_idle_users completed ===");
         }
     }
-    pub fn deactivate_idle_users(&self, db: &UserDatabase) {
-        unsafe {
-            let db_ptr = db as *const UserDatabase as *mut UserDatabaseT;
-            deactivate_users(db_ptr);
+    pub fn deactivate_idle_users(&mut self, db: &mut UserDatabase) {
+        // sessions past their lifetime end here, a user whose current one ended moves on to
+        // their newest live session or goes inactive, in either backend
+        let day = self.clock.borrow().day();
+        let ended: HashSet<String> = self.sessions.end_day(day).into_iter().map(|session| session.token).collect();
+        for user in db.iter_mut() {
+            if ended.contains(&byte_to_string(&user.session_token)) {
+                match self.sessions.live_for(&byte_to_string(&user.username)).last() {
+                    Some(newest) => copy_string(&mut user.session_token, &newest.token),
+                    None => user.is_active = 0,
+                }
+            }
         }
-    }
-    pub fn create_session_for_c_ptr(&self, user: *const UserStructT) -> Result<String, String> {
-        unsafe {
-            let token_ptr = create_user_session(user);
-            if token_ptr.is_null() {
-                return Err("Failed to create session".to_string());
+        for token in &ended {
+            let Ok(c_token) = to_c_string(token, "token") else {
+                continue;
+            };
+            unsafe {
+                let user = find_user_by_session_token(self.db, c_token.as_ptr());
+                if user.is_null() {
+                    continue;
+                }
+                let username = byte_to_string(&(*user).username);
+                match self.sessions.live_for(&username).last() {
+                    Some(newest) => copy_string(&mut (*user).session_token, &newest.token),
+                    None => (*user).is_active = 0,
+                }
             }
-            let token = CStr::from_ptr(token_ptr).to_string_lossy().to_string();
-            Ok(token)
         }
     }
+    /// Users the C table still has room for
+    pub fn free_slots(&self) -> usize {
+        MAX_USERS.saturating_sub(unsafe { (*self.db).count }.max(0) as usize)
+    }
     pub fn print_database_full(&self) {
         unsafe {
             print_database(self.db);
         }
     }
-    pub fn get_user_references_for_sharing(&self) -> Vec<*mut UserStructT> {
+    /// Marks every C user shared and returns a handle to each, holding the reference C added
+    pub fn get_user_references_for_sharing(&self) -> Vec<CUserHandle> {
         let mut count: c_int = 0;
         let refs = unsafe { 
             get_user_references_for_sharing(self.db, &mut count as *mut c_int)
@@ -304,8 +533,22 @@ impl DatabaseExtensions {
         };
         
         for &user_ptr in refs_slice {
-            if !user_ptr.is_null() {
-                result.push(user_ptr);  // Just return pointers, don't create Boxes
+            if user_ptr.is_null() {
+                continue;
+            }
+            // C made the Share itself, check it left the user where the state machine would
+            let shared = unsafe { Ownership::of(&*user_ptr) };
+            match shared {
+                Ok(ownership) if ownership.kind == OwnershipType::SharedCPrimary && ownership.ref_count > 1 => {
+                    result.push(unsafe { CUserHandle::adopt(self.db, Rc::downgrade(&self.table), user_ptr) });
+                }
+                // not adopted, a reference C miscounted is leaked rather than dropped twice
+                Ok(ownership) => println!(
+                    "[Ownership Error] C shared user {} as {:?}",
+                    byte_to_string(unsafe { &(*user_ptr).username }),
+                    ownership
+                ),
+                Err(e) => println!("[Ownership Error] {}", e),
             }
         }
         
@@ -317,6 +560,145 @@ impl DatabaseExtensions {
         result
     }
     
+    // copies of the users C is responsible for, handles to shared Rust users are skipped
+    pub fn export_c_users(&self) -> Vec<UserStruct> {
+        let count = unsafe { get_non_null_ref_count(self.db) };
+        let refs = unsafe { get_user_reference_for_debugging(self.db) };
+        if refs.is_null() {
+            return Vec::new();
+        }
+        let refs_slice = unsafe { std::slice::from_raw_parts(refs, count as usize) };
+        let mut users = Vec::new();
+        for &user_ptr in refs_slice {
+            let c_user = unsafe { &*user_ptr };
+            if !is_c_primary(c_user) {
+                continue;
+            }
+            users.push(UserStruct {
+                password_hash: c_user.password_hash,
+                password_salt: c_user.password_salt,
+                username: c_user.username,
+                user_id: c_user.user_id,
+                email: c_user.email,
+                inactivity_count: c_user.inactivity_count,
+                is_active: c_user.is_active,
+                session_token: c_user.session_token,
+                ownership: c_user.ownership,
+                ref_count: c_user.ref_count,
+            });
+        }
+        unsafe {
+            libc::free(refs as *mut libc::c_void);
+        }
+        users
+    }
+
+    // recreates a user saved by export_c_users, keeping its id and state
+    pub fn restore_c_user(&self, user: &UserStruct) -> Result<(), DatabaseError> {
+        let c_username = to_c_string(&byte_to_string(&user.username), "username")?;
+        let c_email = to_c_string(&byte_to_string(&user.email), "email")?;
+        unsafe {
+            let c_user = create_user(
+                c_username.as_ptr(),
+                c_email.as_ptr(),
+                user.user_id,
+                user.password_hash.as_ptr(),
+                user.password_salt.as_ptr(),
+            );
+            if c_user.is_null() {
+                return Err(DatabaseError::BackendFailure("create_user returned NULL".to_string()));
+            }
+            if add_user(self.db, c_user) != 0 {
+                free_user(c_user);
+                return Err(DatabaseError::Full);
+            }
+            // add_user hands out the next id, put the saved one back
+            (*c_user).user_id = user.user_id;
+            (*c_user).inactivity_count = user.inactivity_count;
+            (*c_user).is_active = user.is_active;
+            (*c_user).session_token = user.session_token;
+        }
+        Ok(())
+    }
+
+    pub fn export_sessions(&self) -> Vec<Session> {
+        self.sessions.iter().cloned().collect()
+    }
+
+    pub fn restore_session(&mut self, session: Session) -> Result<(), DatabaseError> {
+        self.sessions.restore(session)
+    }
+
+    /// Redo a C backend login: mark the user active and put its session back
+    pub fn restore_c_login(&mut self, username: &str, token: &str, client: &str) -> Result<(), DatabaseError> {
+        let user = self.get_user_in_c_backend(username);
+        if user.is_null() {
+            return Err(DatabaseError::NotFound(format!("User {}", username)));
+        }
+        let user_id = unsafe { (*user).user_id };
+        // a login the session table refuses leaves the user as it was
+        self.restore_login(Session::new(user_id, Backend::C, username, token, client, 0))?;
+        unsafe { mark_logged_in(user, token) };
+        Ok(())
+    }
+
+    /// Put back a session minted earlier as if it were just created
+    pub fn restore_login(&mut self, mut session: Session) -> Result<(), DatabaseError> {
+        session.created_day = self.clock.borrow().day();
+        self.sessions.login(session)
+    }
+
+    pub fn session(&self, token: &str) -> Option<&Session> {
+        self.sessions.get(token)
+    }
+
+    /// Live sessions of `username`, oldest first
+    pub fn sessions_of(&self, username: &str) -> Vec<Session> {
+        self.sessions.live_for(username).into_iter().cloned().collect()
+    }
+
+    pub fn rename_sessions(&mut self, old: &str, new: &str) {
+        self.sessions.rename(old, new);
+    }
+
+    /// End the live sessions matching `ends`, see SessionManager::revoke_where
+    pub fn revoke_sessions(&mut self, ends: impl Fn(&Session) -> bool) -> Vec<Session> {
+        self.sessions.revoke_where(ends)
+    }
+
+    // a C user that has moved on to another token keeps it
+    pub fn replace_c_session_token(&self, username: &str, old_token: &str, new_token: &str) {
+        let user = self.get_user_in_c_backend(username);
+        if user.is_null() {
+            return;
+        }
+        unsafe {
+            if byte_to_string(&(*user).session_token) == old_token {
+                copy_string(&mut (*user).session_token, new_token);
+            }
+        }
+    }
+
+    // must run before a purged Rust user is dropped
+    /// Frees a user Rust purged, after dropping every C reference to it. If the hand-off is
+    /// refused the user is leaked instead, C may still point at it.
+    pub fn release_rust_user(&self, mut user: Box<UserStruct>) -> Result<(), DatabaseError> {
+        let user_ptr: *mut UserStructT = &mut *user;
+        let released = unsafe {
+            let releases = vec![Transfer::Release { by: Side::C }; listings(self.db, user_ptr)];
+            hand_off(user_ptr, &releases, || release_rust_user(self.db, user_ptr))
+        };
+        let mut user = Some(user);
+        let freed = released.and_then(|()| unsafe {
+            hand_off(user_ptr, &[Transfer::Free { by: Side::Rust }], || drop(user.take()))
+        });
+        if let Some(user) = user {
+            Box::leak(user);
+        }
+        freed
+    }
+
+    #[allow(dead_code)]
     pub fn add_shared_user_from_rust(&self, user: *mut UserStructT) {
         unsafe {
             add_shared_user_from_rust(self.db, user);
@@ -324,7 +706,6 @@ impl DatabaseExtensions {
     }
 }
 
-pub fn initialize_enhanced_database(dc : &i32) -> DatabaseExtensions {
-    let dc_ptr = dc as *const i32;
-    DatabaseExtensions::new(dc_ptr)
+pub fn initialize_enhanced_database(clock: SharedClock) -> DatabaseExtensions {
+    DatabaseExtensions::new(clock)
 }
\ No newline at end of file
@@ -0,0 +1,112 @@
+// How a join settles a username both backends hold as different users. The losing record is
+// overwritten with the winner, so afterwards both backends agree and the join shares and
+// imports as if there had been no conflict.
+use std::fmt;
+
+use crate::wal::Backend;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum JoinPolicy {
+    // what the join did before policies existed, the C record was never imported
+    #[default]
+    PreferRust,
+    PreferC,
+    // the record whose current session was used last, then the lower inactivity
+    MostRecentlyActive,
+    LowestInactivity,
+    // any conflict stops the join before either half runs
+    FailOnConflict,
+}
+
+// what the policies compare about each side's record
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Activity {
+    // use counter of the record's current session, None without one
+    pub last_used: Option<u64>,
+    pub inactivity: i32,
+}
+
+impl JoinPolicy {
+    /// The record that survives, None under FailOnConflict. Ties go to Rust.
+    pub fn winner(self, rust: Activity, c: Activity) -> Option<Backend> {
+        let c_wins = match self {
+            JoinPolicy::PreferRust => false,
+            JoinPolicy::PreferC => true,
+            JoinPolicy::MostRecentlyActive => {
+                (c.last_used, -c.inactivity) > (rust.last_used, -rust.inactivity)
+            }
+            JoinPolicy::LowestInactivity => c.inactivity < rust.inactivity,
+            JoinPolicy::FailOnConflict => return None,
+        };
+        Some(if c_wins { Backend::C } else { Backend::Rust })
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct JoinConflict {
+    pub username: String,
+    pub rust: Activity,
+    pub c: Activity,
+    // the backend whose record was kept, None if the join was stopped instead
+    pub winner: Option<Backend>,
+}
+
+impl fmt::Display for JoinConflict {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self.winner {
+            Some(backend) => write!(f, "{}: kept the {:?} record", self.username, backend),
+            None => write!(f, "{}: unresolved", self.username),
+        }?;
+        write!(f, " (inactivity Rust {}, C {})", self.rust.inactivity, self.c.inactivity)
+    }
+}
+
+#[derive(Debug, Clone, Default)]
+pub struct JoinReport {
+    pub day: i32,
+    pub policy: JoinPolicy,
+    // Rust users newly listed in the C table
+    pub shared: usize,
+    // C users copied into Rust
+    pub imported: usize,
+    // every username the backends held as different users, in C table order
+    pub conflicts: Vec<JoinConflict>,
+    // FailOnConflict found a conflict, nothing was shared or imported
+    pub aborted: bool,
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn activity(last_used: Option<u64>, inactivity: i32) -> Activity {
+        Activity { last_used, inactivity }
+    }
+
+    #[test]
+    fn each_policy_picks_its_winner() {
+        // C was used more recently, Rust has been inactive for less
+        let rust = activity(Some(3), 1);
+        let c = activity(Some(7), 4);
+        assert_eq!(JoinPolicy::PreferRust.winner(rust, c), Some(Backend::Rust));
+        assert_eq!(JoinPolicy::PreferC.winner(rust, c), Some(Backend::C));
+        assert_eq!(JoinPolicy::MostRecentlyActive.winner(rust, c), Some(Backend::C));
+        assert_eq!(JoinPolicy::LowestInactivity.winner(rust, c), Some(Backend::Rust));
+        assert_eq!(JoinPolicy::FailOnConflict.winner(rust, c), None);
+    }
+
+    #[test]
+    fn most_recently_active_prefers_a_session_then_lower_inactivity() {
+        let policy = JoinPolicy::MostRecentlyActive;
+        assert_eq!(policy.winner(activity(None, 0), activity(Some(0), 9)), Some(Backend::C));
+        assert_eq!(policy.winner(activity(Some(5), 2), activity(Some(5), 1)), Some(Backend::C));
+        assert_eq!(policy.winner(activity(None, 1), activity(None, 2)), Some(Backend::Rust));
+    }
+
+    #[test]
+    fn ties_go_to_rust() {
+        let same = activity(Some(2), 2);
+        assert_eq!(JoinPolicy::MostRecentlyActive.winner(same, same), Some(Backend::Rust));
+        assert_eq!(JoinPolicy::LowestInactivity.winner(same, same), Some(Backend::Rust));
+    }
+}
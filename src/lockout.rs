@@ -0,0 +1,160 @@
+// Failed-login tracking. Keyed by username, so Rust-held and C-held users are treated the same.
+use std::collections::HashMap;
+
+use crate::database_fix_full::DatabaseError;
+
+// defaults for LockoutPolicy
+pub const MAX_FAILED_LOGINS: usize = 5;
+pub const FAILED_LOGIN_WINDOW_DAYS: i32 = 1;
+pub const LOCKOUT_DAYS: i32 = 1;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct LockoutPolicy {
+    // this many wrong passwords within the window locks the account
+    pub max_failures: usize,
+    // failures count for this many days, 1 is the current day only
+    pub window_days: i32,
+    // days until a locked account unlocks by itself
+    pub lockout_days: i32,
+}
+
+impl Default for LockoutPolicy {
+    fn default() -> Self {
+        LockoutPolicy {
+            max_failures: MAX_FAILED_LOGINS,
+            window_days: FAILED_LOGIN_WINDOW_DAYS,
+            lockout_days: LOCKOUT_DAYS,
+        }
+    }
+}
+
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct LoginFailures {
+    pub username: String,
+    // day of each recent failure, oldest first
+    pub failure_days: Vec<i32>,
+    // first day the account can log in again
+    pub locked_until: Option<i32>,
+}
+
+#[derive(Default)]
+pub struct LoginGuard {
+    policy: LockoutPolicy,
+    users: HashMap<String, LoginFailures>,
+}
+
+impl LoginGuard {
+    pub fn set_policy(&mut self, policy: LockoutPolicy) {
+        self.policy = policy;
+    }
+
+    /// Err(AccountLocked) while `username` is locked out, checked before the password is
+    pub fn check(&self, username: &str, today: i32) -> Result<(), DatabaseError> {
+        match self.users.get(username).and_then(|record| record.locked_until) {
+            Some(until_day) if today < until_day => Err(DatabaseError::AccountLocked { until_day }),
+            _ => Ok(()),
+        }
+    }
+
+    /// Count a wrong password, locking the account once the policy says so. Returns the day
+    /// it unlocks if this failure locked it.
+    pub fn record_failure(&mut self, username: &str, today: i32) -> Option<i32> {
+        let policy = self.policy;
+        let record = self.users.entry(username.to_string()).or_insert_with(|| LoginFailures {
+            username: username.to_string(),
+            ..LoginFailures::default()
+        });
+        if record.locked_until.is_some_and(|until_day| today >= until_day) {
+            // automatic unlock, the old failures don't count towards the next lock
+            record.locked_until = None;
+            record.failure_days.clear();
+        }
+        record.failure_days.retain(|&day| today - day < policy.window_days);
+        record.failure_days.push(today);
+        if record.locked_until.is_none() && record.failure_days.len() >= policy.max_failures {
+            record.locked_until = Some(today + policy.lockout_days);
+            return record.locked_until;
+        }
+        None
+    }
+
+    /// A successful login forgets earlier failures
+    pub fn record_success(&mut self, username: &str) {
+        self.users.remove(username);
+    }
+
+    /// Carry `old`'s failures and any lock over to `new`, whatever was recorded under `new`
+    /// goes
+    pub fn rename(&mut self, old: &str, new: &str) {
+        self.users.remove(new);
+        if let Some(mut record) = self.users.remove(old) {
+            record.username = new.to_string();
+            self.users.insert(new.to_string(), record);
+        }
+    }
+
+    pub fn failures(&self) -> impl Iterator<Item = &LoginFailures> {
+        self.users.values()
+    }
+
+    pub fn restore(&mut self, record: LoginFailures) {
+        self.users.insert(record.username.clone(), record);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn guard(max_failures: usize, window_days: i32, lockout_days: i32) -> LoginGuard {
+        let mut guard = LoginGuard::default();
+        guard.set_policy(LockoutPolicy { max_failures, window_days, lockout_days });
+        guard
+    }
+
+    #[test]
+    fn locks_at_the_threshold() {
+        let mut guard = guard(3, 1, 2);
+        assert_eq!(guard.record_failure("alice", 5), None);
+        assert_eq!(guard.record_failure("alice", 5), None);
+        assert_eq!(guard.check("alice", 5), Ok(()));
+        assert_eq!(guard.record_failure("alice", 5), Some(7));
+        assert_eq!(guard.check("alice", 5), Err(DatabaseError::AccountLocked { until_day: 7 }));
+        // other users are untouched
+        assert_eq!(guard.check("bob", 5), Ok(()));
+    }
+
+    #[test]
+    fn failures_outside_the_window_age_out() {
+        let mut guard = guard(3, 2, 1);
+        guard.record_failure("alice", 1);
+        guard.record_failure("alice", 2);
+        // day 1 is out of a two day window by day 3
+        assert_eq!(guard.record_failure("alice", 3), None);
+        assert_eq!(guard.check("alice", 3), Ok(()));
+        assert_eq!(guard.record_failure("alice", 3), Some(4));
+    }
+
+    #[test]
+    fn unlocks_on_until_day() {
+        let mut guard = guard(2, 1, 3);
+        guard.record_failure("alice", 0);
+        assert_eq!(guard.record_failure("alice", 0), Some(3));
+        assert!(guard.check("alice", 2).is_err());
+        assert_eq!(guard.check("alice", 3), Ok(()));
+        // the failures that locked it don't count towards the next lock
+        assert_eq!(guard.record_failure("alice", 3), None);
+        assert_eq!(guard.check("alice", 3), Ok(()));
+    }
+
+    #[test]
+    fn success_resets_the_count() {
+        let mut guard = guard(2, 1, 1);
+        guard.record_failure("alice", 0);
+        guard.record_success("alice");
+        assert_eq!(guard.record_failure("alice", 0), None);
+        assert_eq!(guard.failures().count(), 1);
+        guard.record_success("alice");
+        assert_eq!(guard.failures().count(), 0);
+    }
+}
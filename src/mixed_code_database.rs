@@ -1,11 +1,23 @@
+mod clock;
 mod database_fix_full;
 mod database_wrapper;
 mod generated_data;
+mod join;
+mod lockout;
+mod ownership;
+mod password_policy;
+mod reconcile;
+mod session;
+mod signed_token;
+mod snapshot;
+mod user_io;
+mod wal;
 
 struct UserEntry {
     email: Option<String>,
     username: String,
     password: String,
+    #[allow(dead_code)] // generated from the YAML, not used by the simulation
     id: Option<i32>,
 }
 
@@ -17,14 +29,30 @@ struct DayData {
 
 const MAX_USERS: usize = 1000;
 const SESSION_TOKEN_MAX_LEN: usize = 32;
-const MAX_PASSWORD_LENGTH: usize = 100;
 
 use database_fix_full::{
-    add_user, create_user, find_user_by_username, find_user_by_username_mut, update_database_daily, UserDatabase, UserStruct,
+    add_user, create_user, find_user_by_email, is_purgeable, find_user_by_username, find_user_by_username_mut,
+    rename_user, restore_user, set_user_id, update_database_daily, verify_credentials, DatabaseError,
+    OwnershipType, UserDatabase, UserStruct,
 };
 use database_wrapper::{
-    initialize_enhanced_database, DatabaseExtensions, UserReference, UserStructT,
+    initialize_enhanced_database, CUserHandle, DatabaseExtensions, UserStructT,
 };
+use clock::{Clock, ManualClock, SharedClock};
+use session::{
+    EvictionPolicy, Session, SessionInfo, SessionLifetimes, SessionState, SessionStatus, TokenMode,
+    DEFAULT_CLIENT,
+};
+use join::{Activity, JoinConflict, JoinPolicy, JoinReport};
+use lockout::{LockoutPolicy, LoginGuard};
+use password_policy::PasswordPolicy;
+use reconcile::{Discrepancy, Finding, ReconcileReport};
+use snapshot::Snapshot;
+use std::collections::{BTreeMap, HashSet};
+use std::io::{Read, Write};
+use user_io::{ImportReport, UserFormat, UserRecord};
+use wal::{Backend, Wal, WalRecord};
+use std::path::Path;
 
 pub struct UserInfoT<'a> {
     // Add lifetime parameter
@@ -33,14 +61,23 @@ pub struct UserInfoT<'a> {
     password: &'a str,
 }
 
+// fields drop in order, the C table goes before the Rust users it lists
 pub struct EnhancedStudentDatabase {
-    rust_db: Box<UserDatabase>,
+    user_references: Vec<CUserHandle>,
     c_extensions: DatabaseExtensions,
-    user_references: Vec<UserReference>,
+    rust_db: Box<UserDatabase>,
     session_tokens: Vec<String>,
     pending_requests: Vec<UserInfoT<'static>>,
-    _day_counter: Box<i32>,
+    clock: SharedClock,
     c_allocated_users: Vec<i32>,
+    unique_emails: bool,
+    login_guard: LoginGuard,
+    password_policy: PasswordPolicy,
+    join_policy: JoinPolicy,
+    last_join: Option<JoinReport>,
+    wal: Option<Wal>,
+    // lsn of the last logged change the in-memory state reflects
+    applied_lsn: u64,
 }
 
 pub fn str_cmp(a: &[u8], b: &str) -> bool {
@@ -51,6 +88,13 @@ pub fn bytes_to_string(bytes: &[u8]) -> String {
     let end = bytes.iter().position(|&b| b == 0).unwrap_or(bytes.len());
     String::from_utf8_lossy(&bytes[..end]).to_string()
 }
+// the same signup held twice, a C user and the copy a join made of it
+pub fn same_user(a: &UserStruct, b: &UserStruct) -> bool {
+    a.username == b.username
+        && a.email == b.email
+        && a.password_salt == b.password_salt
+        && a.password_hash == b.password_hash
+}
 pub fn string_to_bytes(s: String) -> [u8; SESSION_TOKEN_MAX_LEN] {
     let mut byte_array = [0u8; SESSION_TOKEN_MAX_LEN];
     let bytes = s.as_bytes();
@@ -61,30 +105,84 @@ pub fn string_to_bytes(s: String) -> [u8; SESSION_TOKEN_MAX_LEN] {
 }
 
 impl EnhancedStudentDatabase {
-    /// Initialize a new enhanced database instance
+    /// Initialize a new enhanced database instance starting at day 0
     pub fn new() -> Self {
-        let dc = Box::new(0);  
+        Self::with_clock(Box::<ManualClock>::default())
+    }
+
+    /// Initialize a new enhanced database instance, both backends read the day from `clock`
+    pub fn with_clock(clock: Box<dyn Clock>) -> Self {
+        let clock = clock::shared(clock);
         let rust_db = database_fix_full::init_database();
         println!("Created Rust Db");
-        let c_extensions = initialize_enhanced_database(&dc);
+        let c_extensions = initialize_enhanced_database(clock.clone());
         println!("!created C dab");
-        let std_b = EnhancedStudentDatabase {
+        EnhancedStudentDatabase {
             rust_db,
             user_references: Vec::new(),
             session_tokens: Vec::new(),
             pending_requests: Vec::new(),
-            _day_counter: Box::new(0),
+            clock,
             c_extensions,
             c_allocated_users: Vec::new(),
-        };
-        std_b
+            unique_emails: false,
+            login_guard: LoginGuard::default(),
+            password_policy: PasswordPolicy::default(),
+            join_policy: JoinPolicy::default(),
+            last_join: None,
+            wal: None,
+            applied_lsn: 0,
+        }
+    }
+    pub fn day(&self) -> i32 {
+        self.clock.borrow().day()
+    }
+
+    /// What to do when a login finds the session table full, evicting the least recently
+    /// used session by default
+    pub fn set_session_eviction(&mut self, policy: EvictionPolicy) {
+        self.c_extensions.set_session_eviction(policy);
+    }
+
+    /// Reject signups whose email is already registered, off by default
+    pub fn set_unique_emails(&mut self, enabled: bool) {
+        self.unique_emails = enabled;
+    }
+    // usernames (and emails when enabled) must be unique across both backends. They are
+    // length checked first, both backends would store a longer one cut short.
+    fn check_signup_conflict(&self, username: &str, email: &str) -> Result<(), DatabaseError> {
+        user_io::validate_username(username)?;
+        user_io::validate_email(email)?;
+        if find_user_by_username(&self.rust_db, username).is_some()
+            || !self.c_extensions.get_user_in_c_backend(username).is_null()
+        {
+            return Err(DatabaseError::Duplicate { field: "username", value: username.to_string() });
+        }
+        if self.unique_emails
+            && (find_user_by_email(&self.rust_db, email).is_some()
+                || !self.c_extensions.get_user_in_c_backend_by_email(email).is_null())
+        {
+            return Err(DatabaseError::Duplicate { field: "email", value: email.to_string() });
+        }
+        Ok(())
     }
     pub fn enqueue_user(
         &mut self,
         username: &'static str,
         email: &'static str,
         password: &'static str,
-    ) -> Result<(), String> {
+    ) -> Result<(), DatabaseError> {
+        self.password_policy.check(password)?;
+        self.check_signup_conflict(username, email)?;
+        // the backends only see queued users at the next sync
+        for pending in &self.pending_requests {
+            if pending.username == username {
+                return Err(DatabaseError::Duplicate { field: "username", value: username.to_string() });
+            }
+            if self.unique_emails && pending.email == email {
+                return Err(DatabaseError::Duplicate { field: "email", value: email.to_string() });
+            }
+        }
         let user_info = UserInfoT {
             email,
             username,
@@ -95,14 +193,20 @@ impl EnhancedStudentDatabase {
     }
 
     // Read Only : Dont Change
-    pub fn sync_database(&mut self) {
+    /// Signs up every queued user, returning the (username, error) of each one rejected
+    pub fn sync_database(&mut self) -> Vec<(String, DatabaseError)> {
         //Signup all pending users
         let drained_users: Vec<_> = self.pending_requests.drain(..).collect();
+        let mut rejected = Vec::new();
         for (_i, user) in drained_users.iter().enumerate() {
             let pending_count = drained_users.len() - _i;
-            let _ =
-                self.add_user_with_sync(user.username, user.email, user.password, pending_count);
+            if let Err(e) =
+                self.add_user_with_sync(user.username, user.email, user.password, pending_count)
+            {
+                rejected.push((user.username.to_string(), e));
+            }
         }
+        rejected
     }
     // Read Only : Dont Change
     pub fn activate_user(&mut self, user_name: &str) {
@@ -116,28 +220,27 @@ impl EnhancedStudentDatabase {
         email: &str,
         password: &str,
         pending_count: usize,
-    ) -> Result<(), String> {
+    ) -> Result<(), DatabaseError> {
+        // checked before picking a backend so the heuristic can't route around it
+        self.password_policy.check(password)?;
+        self.check_signup_conflict(username, email)?;
         // Intelligent load balancing - use C allocator when under pressure
-        if pending_count > 5 || self.rust_db.count >= MAX_USERS as i32 {
+        let record = if pending_count > 5 || self.rust_db.count >= MAX_USERS as i32 {
             // println!(
             //     // "[System] High load detected, using optimized C allocator for user {}",
             //     username
             // );
-            self.c_extensions
-                .sync_user_to_c_backend(username, email, 0, password)?;
-            let id = self.c_extensions.get_last_user_id();
-            self.c_allocated_users.push(id);
-            return Ok(());
-        }
-
-        let user = create_user(username, email, 0, password);
-        add_user(&mut self.rust_db, user);
-
+            let user = self.c_extensions.prepare_c_user(username, email, password)?;
+            WalRecord::Signup { backend: Backend::C, user }
+        } else {
+            let user = create_user(username, email, self.rust_db.next_user_id(), password)?;
+            WalRecord::Signup { backend: Backend::Rust, user: *user }
+        };
         // println!(
         //     "[System] Added user {} using dual allocation strategy",
         //     username
         // );
-        Ok(())
+        self.log_and_apply(record)
     }
 
     pub fn find_user_by_name<'a>(
@@ -145,14 +248,21 @@ impl EnhancedStudentDatabase {
         db: &'a UserDatabase,
         username: &str,
     ) -> Option<&'a UserStruct> {
-        for i in 0..db.count as usize {
-            if let Some(ref user) = db.users[i] {
-                if str_cmp(&user.username, username) {
-                    return Some(user);
-                }
-            }
+        find_user_by_username(db, username)
+    }
+    /// Give a Rust user a new name, its sessions, refresh tokens and any lockout go with it
+    pub fn rename_user(&mut self, old_name: &str, new_name: &str) -> Result<(), DatabaseError> {
+        // checked up front, a logged rename has to apply
+        user_io::validate_username(new_name)?;
+        if find_user_by_username(&self.rust_db, old_name).is_none() {
+            return Err(DatabaseError::NotFound(format!("User {}", old_name)));
+        }
+        if (new_name != old_name && find_user_by_username(&self.rust_db, new_name).is_some())
+            || !self.c_extensions.get_user_in_c_backend(new_name).is_null()
+        {
+            return Err(DatabaseError::Duplicate { field: "username", value: new_name.to_string() });
         }
-        None
+        self.log_and_apply(WalRecord::Rename { old: old_name.to_string(), new: new_name.to_string() })
     }
     fn update_user_session_token(&mut self, user_name: &str, token: String) {
         if let Some(user) = find_user_by_username_mut(&mut self.rust_db, user_name) {
@@ -163,132 +273,870 @@ impl EnhancedStudentDatabase {
         }
     }
     /// Read Only: Dont Modify Authenticate user and create session
-    pub fn login_user(&mut self, user_name: &str, password: &str) -> Result<String, String> {
-        if self.find_user_by_name(&self.rust_db, user_name).is_none() {
-            // User found in C backend cache
-            for user_ref in self.user_references.iter_mut() {
-                if str_cmp((*user_ref).username.as_bytes(), user_name) {
-                    if self.c_extensions.get_user_password(user_ref.ptr) != password {
-                        return Err("Incorrect password".to_string());
-                    }
-                    unsafe {
-                        (*user_ref.ptr).inactivity_count = 0;
-                        (*user_ref.ptr).is_active = 1;
-                    }
-                    let session_token = self.c_extensions.create_session_for_c_ptr(user_ref.ptr)?;
-                    return Ok(session_token);
+    pub fn login_user(&mut self, user_name: &str, password: &str) -> Result<String, DatabaseError> {
+        self.login_user_from(user_name, password, DEFAULT_CLIENT)
+    }
+
+    /// `login_user` from a named device or client. Each client gets its own session next to the
+    /// user's others, logging in again from the same client replaces that client's session.
+    /// Wrong passwords count against the account, see `set_lockout_policy`.
+    pub fn login_user_from(&mut self, user_name: &str, password: &str, client: &str) -> Result<String, DatabaseError> {
+        self.login_guard.check(user_name, self.day())?;
+        match self.password_login(user_name, password, client) {
+            Ok(token) => {
+                self.login_guard.record_success(user_name);
+                Ok(token)
+            }
+            Err(DatabaseError::IncorrectPassword) => {
+                let record = WalRecord::LoginFailed { username: user_name.to_string() };
+                self.log(&record)?;
+                if let Some(until_day) = self.login_guard.record_failure(user_name, self.day()) {
+                    println!("[Lockout] Account {} locked until day {}", user_name, until_day);
                 }
+                Err(DatabaseError::IncorrectPassword)
             }
+            Err(e) => Err(e),
+        }
+    }
 
-            let user = self.c_extensions.get_user_in_c_backend(user_name);
-            if user == std::ptr::null_mut() {
-                return Err("User not found in any backend".to_string());
-            }
+    /// How many wrong passwords lock an account, for how long and how far back they count.
+    /// Not persisted, like the other settings.
+    pub fn set_lockout_policy(&mut self, policy: LockoutPolicy) {
+        self.login_guard.set_policy(policy);
+    }
 
-            self.user_references
-                .push(UserReference::new(String::from(user_name), user));
+    /// Rules every signup password must meet, checked when the user is queued and again when
+    /// synced. Imported users supplying a password are held to it too. Not persisted.
+    pub fn set_password_policy(&mut self, policy: PasswordPolicy) {
+        self.password_policy = policy;
+    }
 
-            let user_password = self.c_extensions.get_user_password(user);
+    /// Which record a join keeps when both backends hold a different user under the same
+    /// name, the Rust one by default. Not persisted.
+    pub fn set_join_policy(&mut self, policy: JoinPolicy) {
+        self.join_policy = policy;
+    }
 
-            if user_password == password {
-                return self.c_extensions.login_user(user_name);
-            } else {
-                return Err("Incorrect password".to_string());
-            }
+    /// What the most recent join shared, imported and how it settled each conflict
+    pub fn last_join_report(&self) -> Option<&JoinReport> {
+        self.last_join.as_ref()
+    }
+
+    // the token is minted and logged before the session exists, apply installs it
+    fn password_login(&mut self, user_name: &str, password: &str, client: &str) -> Result<String, DatabaseError> {
+        let (user_id, backend) = if let Some(user) = self.find_user_by_name(&self.rust_db, user_name) {
+            let user_id = user.user_id;
+            verify_credentials(&self.rust_db, user_name, password)?;
+            (user_id, self.owner_of(user_name))
         } else {
-            let user = find_user_by_username(&self.rust_db, user_name).unwrap();
-            if str_cmp(&user.password, password) {
-                // println!("User[{}] {} logged in successfully", user.user_id, user_name);
-                let session_token = self.c_extensions.create_session(user)?;
-                self.update_user_session_token(user_name, session_token.clone());
-                self.activate_user(user_name);
-                return Ok(session_token);
-            } else {
-                return Err("Incorrect password".to_string());
-            }
-        }
+            // User found in C backend cache
+            let cached = self
+                .user_references
+                .iter()
+                .find(|handle| handle.username().is_ok_and(|name| str_cmp(name.as_bytes(), user_name)));
+            let user_id = match cached {
+                Some(handle) => handle.user_id()?,
+                None => {
+                    let handle = self
+                        .c_extensions
+                        .c_user_handle(user_name)?
+                        .ok_or_else(|| DatabaseError::NotFound(format!("User {}", user_name)))?;
+                    let user_id = handle.user_id()?;
+                    self.user_references.push(handle);
+                    user_id
+                }
+            };
+            self.c_extensions.verify_credentials(user_name, password)?;
+            (user_id, Backend::C)
+        };
+        let session_token = self.c_extensions.mint_session_token(user_id, backend)?;
+        self.log_and_apply(WalRecord::Login {
+            username: user_name.to_string(),
+            token: session_token.clone(),
+            client: client.to_string(),
+        })?;
+        Ok(session_token)
     }
     // Read Only : Dont Change
+    /// (username, email, inactivity) of each C user with a cached handle, skipping users C has
+    /// released since they were cached
+    pub fn cached_c_users(&self) -> Vec<(String, String, i32)> {
+        self.user_references
+            .iter()
+            .filter_map(|handle| Some((handle.username().ok()?, handle.email().ok()?, handle.inactivity_count().ok()?)))
+            .collect()
+    }
+
     pub fn join_databases(&mut self) {
+        let mut report = JoinReport { day: self.day(), policy: self.join_policy, ..JoinReport::default() };
+        // Copies of the C users, lookups check Rust first so once imported they find the copy
+        let all_c_userstructs = self.c_extensions.get_all_user_references();
+
+        // Settle every name both backends hold as different users before either half runs
+        for c_user in &all_c_userstructs {
+            let username = bytes_to_string(&c_user.username);
+            let Some(rust_user) = find_user_by_username(&self.rust_db, &username) else {
+                continue;
+            };
+            if same_user(rust_user, c_user) {
+                continue;
+            }
+            let rust = self.activity(rust_user);
+            let c = self.activity(c_user);
+            report.conflicts.push(JoinConflict { username, rust, c, winner: self.join_policy.winner(rust, c) });
+        }
+        if report.conflicts.iter().any(|conflict| conflict.winner.is_none()) {
+            for conflict in &report.conflicts {
+                println!("[Join Conflict] {}", conflict);
+            }
+            println!("[Join Error] {} conflicting users, join skipped", report.conflicts.len());
+            report.aborted = true;
+            self.last_join = Some(report);
+            return;
+        }
+        // The loser takes the winner's record, keeping its own id and ownership
+        for conflict in &report.conflicts {
+            println!("[Join Conflict] {}", conflict);
+            let c_user = all_c_userstructs
+                .iter()
+                .find(|user| str_cmp(&user.username, &conflict.username))
+                .expect("conflicts come from the C copies");
+            let result = match conflict.winner {
+                Some(Backend::C) => find_user_by_username_mut(&mut self.rust_db, &conflict.username)
+                    .map(|rust_user| {
+                        *rust_user = UserStruct {
+                            user_id: rust_user.user_id,
+                            ownership: rust_user.ownership,
+                            ref_count: rust_user.ref_count,
+                            ..c_user.clone()
+                        };
+                    })
+                    .ok_or_else(|| DatabaseError::NotFound(format!("User {}", conflict.username))),
+                _ => {
+                    let rust_user = find_user_by_username(&self.rust_db, &conflict.username)
+                        .expect("conflicts come from the Rust users");
+                    self.c_extensions.replace_c_user(rust_user)
+                }
+            };
+            if let Err(e) = result {
+                println!("[Join Error] Could not resolve conflict on {}: {}", conflict.username, e);
+            }
+        }
+        // Both sides now agree on every conflicting name, the C copies have to follow
+        let all_c_userstructs = self.c_extensions.get_all_user_references();
+
         //Creating shared handles for all users in Rust DB
         println!("Before join: Rust DB has {} users", self.rust_db.count);
         
         // Print first user before join
-        if let Some(ref user) = self.rust_db.users[0] {
+        if let Some(user) = self.rust_db.first() {
             println!("Before join: First user = {}", bytes_to_string(&user.username));
         }
-        print!(
-            "[Info] Creating shared handles for {} rust users\n",
-            (*self.rust_db).count
+        println!(
+            "[Info] Creating shared handles for {} rust users",
+            self.rust_db.count
         );
         // Sync all users from Rust DB to C backend
-        for user_opt in self.rust_db.users.iter().take(self.rust_db.count as usize) {
-            if user_opt.is_none() {
+        for user in self.rust_db.iter() {
+            // C already lists it from an earlier join, or still has the original of a copy
+            let c_user = self.c_extensions.get_user_in_c_backend(&bytes_to_string(&user.username));
+            if !c_user.is_null() && same_user(unsafe { &*c_user }, user) {
                 continue;
             }
-            if let Some(user) = user_opt {
-                let user_ptr = {
-                    let ptr = std::ptr::addr_of!(**user);
-                    ptr as *mut UserStructT
-                };
-                self.c_extensions.sync_user_from_rust_db(user_ptr);
+            let user_ptr = {
+                let ptr = std::ptr::addr_of!(*user);
+                ptr as *mut UserStructT
+            };
+            match self.c_extensions.sync_user_from_rust_db(user_ptr) {
+                Ok(()) => report.shared += 1,
+                Err(e) => println!("[Join Error] Could not share user {}: {}", bytes_to_string(&user.username), e),
             }
         }
         // Now perform the complementary sync from C backend to Rust DB
         println!("[Info] Syncing all user references from C backend...");
 
-        // Get pointer references for C users and extend local references
-        let all_c_userstructs = self.c_extensions.get_all_user_references();
+        self.update_c_originals();
         // add all users in this vector to rust db
         for user in all_c_userstructs {
-            add_user(&mut self.rust_db, user);
+            // C keeps its original until it purges or merges it, an earlier join already copied it.
+            // One Rust purged today is still there until C's daily pass drops it too.
+            if is_purgeable(&user)
+                || find_user_by_username(&self.rust_db, &bytes_to_string(&user.username))
+                    .is_some_and(|existing| same_user(existing, &user))
+            {
+                continue;
+            }
+            // the copy keeps the C id, both backends then hold the same user
+            match restore_user(&mut self.rust_db, Box::new(user)) {
+                Ok(_) => report.imported += 1,
+                Err(e) => println!("[Join Error] Could not import C user: {}", e),
+            }
         }
             println!("After join: Rust DB has {} users", self.rust_db.count);
     
         // Print first user after join
-        if let Some(ref user) = self.rust_db.users[0] {
+        if let Some(user) = self.rust_db.first() {
             println!("After join: First user = {}", bytes_to_string(&user.username));
         } else {
             println!("After join: First user is None!");
         }
+        self.last_join = Some(report);
     }
 
-    pub fn validate_active_user_session(&self) {
-        println!("Starting validate_active_user_session");
-        // Take all users in this database and validate their sessions in C backend
-        for user in self.rust_db.users.iter().take(self.rust_db.count as usize) {
-            if let Some(u) = user {
-                if u.is_active == 1 {
-                    let token_str = bytes_to_string(&u.session_token);
-                    if token_str.is_empty() {
-                        // println!("Skipping validation for user {} - empty token", u.user_id);
-                        continue;
-                    }
-                    // println!("Validating session user {}, token: '{}'", u.user_id, token_str);
-                    // println!("Token length: {}, bytes: {:?}", token_str.len(), u.session_token[0..16].to_vec());
-                    let _ = self.c_extensions.validate_session(bytes_to_string(&u.session_token).as_str());
-                }
+    // Logins and aging land on the Rust copy of a C user, so the original C keeps until it
+    // purges it is brought up to date from the copy
+    fn update_c_originals(&self) {
+        for c_user in self.c_extensions.export_c_users() {
+            let username = bytes_to_string(&c_user.username);
+            let Some(copy) = find_user_by_username(&self.rust_db, &username).filter(|copy| same_user(copy, &c_user)) else {
+                continue;
+            };
+            let result = self
+                .c_extensions
+                .replace_c_user(copy)
+                .and_then(|()| self.c_extensions.set_c_user_id(&username, copy.user_id));
+            if let Err(e) = result {
+                println!("[Sync Error] Could not update C user {}: {}", username, e);
+            }
+        }
+    }
+
+    /// Every way the backends disagree: users only one of them holds, and C users whose Rust
+    /// copy has a different id, email, inactivity, active flag or session token. With a
+    /// `source_of_truth` they are repaired too, a missing user is copied to the backend
+    /// without it, never deleted, and differing values are overwritten with the source's.
+    pub fn reconcile(&mut self, source_of_truth: Option<Backend>) -> Result<ReconcileReport, DatabaseError> {
+        let Some(source) = source_of_truth else {
+            let findings = self
+                .diff_backends()
+                .into_iter()
+                .map(|discrepancy| Finding { discrepancy, repaired: false })
+                .collect();
+            return Ok(ReconcileReport { day: self.day(), source_of_truth, findings });
+        };
+        let record = WalRecord::Reconcile { source };
+        self.log(&record)?;
+        Ok(self.repair_backends(source))
+    }
+
+    // C users first, then the Rust users C doesn't list
+    fn diff_backends(&self) -> Vec<Discrepancy> {
+        let mut found = Vec::new();
+        for c_user in self.c_extensions.export_c_users() {
+            let username = bytes_to_string(&c_user.username);
+            match find_user_by_username(&self.rust_db, &username) {
+                Some(rust_user) => found.extend(reconcile::compare(rust_user, &c_user)),
+                None => found.push(Discrepancy::Missing { username, from: Backend::Rust }),
+            }
+        }
+        for user in self.rust_db.iter() {
+            let username = bytes_to_string(&user.username);
+            if self.c_extensions.get_user_in_c_backend(&username).is_null() {
+                found.push(Discrepancy::Missing { username, from: Backend::C });
             }
         }
+        found
+    }
+
+    // the live path and WAL replay of reconcile, a finding counts as repaired once a fresh
+    // diff no longer has it
+    fn repair_backends(&mut self, source: Backend) -> ReconcileReport {
+        let before = self.diff_backends();
+        // missing users first, a copy can come out with a different id than the original
+        for discrepancy in &before {
+            let Discrepancy::Missing { username, from } = discrepancy else {
+                continue;
+            };
+            let result = match from {
+                Backend::Rust => self.c_copy(username).and_then(|user| {
+                    let user = UserStruct { ownership: OwnershipType::RustOwned as i32, ref_count: 1, ..user };
+                    add_user(&mut self.rust_db, Box::new(user)).map(|_| ())
+                }),
+                Backend::C => match find_user_by_username_mut(&mut self.rust_db, username) {
+                    Some(user) => self.c_extensions.sync_user_from_rust_db(user as *mut UserStructT),
+                    None => Err(DatabaseError::NotFound(format!("User {}", username))),
+                },
+            };
+            if let Err(e) = result {
+                println!("[Reconcile Error] Could not copy {} to {:?}: {}", username, from, e);
+            }
+        }
+        // compare lists a user's fields together, so dedup leaves each name once
+        let mut differing: Vec<String> = self
+            .diff_backends()
+            .iter()
+            .filter(|discrepancy| matches!(discrepancy, Discrepancy::Differs { .. }))
+            .map(|discrepancy| discrepancy.username().to_string())
+            .collect();
+        differing.dedup();
+        for username in differing {
+            if let Err(e) = self.overwrite_from(source, &username) {
+                println!("[Reconcile Error] Could not repair {}: {}", username, e);
+            }
+        }
+        let after = self.diff_backends();
+        let findings = before
+            .into_iter()
+            .map(|discrepancy| Finding { repaired: !after.contains(&discrepancy), discrepancy })
+            .collect();
+        ReconcileReport { day: self.day(), source_of_truth: Some(source), findings }
+    }
+
+    // copies `source`'s record of `username` over the other backend's, ownership stays as it was
+    fn overwrite_from(&mut self, source: Backend, username: &str) -> Result<(), DatabaseError> {
+        let c_user = self.c_copy(username)?;
+        match source {
+            Backend::Rust => {
+                let rust_user = find_user_by_username(&self.rust_db, username)
+                    .ok_or_else(|| DatabaseError::NotFound(format!("User {}", username)))?;
+                self.c_extensions.replace_c_user(rust_user)?;
+                self.c_extensions.set_c_user_id(username, rust_user.user_id)
+            }
+            Backend::C => {
+                let rust_user = find_user_by_username_mut(&mut self.rust_db, username)
+                    .ok_or_else(|| DatabaseError::NotFound(format!("User {}", username)))?;
+                *rust_user = UserStruct {
+                    user_id: rust_user.user_id,
+                    ownership: rust_user.ownership,
+                    ref_count: rust_user.ref_count,
+                    ..c_user.clone()
+                };
+                set_user_id(&mut self.rust_db, username, c_user.user_id)
+            }
+        }
+    }
+
+    // a copy of the user C is responsible for under `username`, not a shared Rust user
+    fn c_copy(&self, username: &str) -> Result<UserStruct, DatabaseError> {
+        self.c_extensions
+            .export_c_users()
+            .into_iter()
+            .find(|user| str_cmp(&user.username, username))
+            .ok_or_else(|| DatabaseError::NotFound(format!("User {}", username)))
+    }
+
+    // what the join policies compare, the session is looked up by the token the record holds
+    fn activity(&self, user: &UserStruct) -> Activity {
+        Activity {
+            last_used: self
+                .c_extensions
+                .session(&bytes_to_string(&user.session_token))
+                .map(Session::last_used),
+            inactivity: user.inactivity_count,
+        }
+    }
+
+    pub fn validate_active_user_session(&mut self) {
+        println!("Starting validate_active_user_session");
+        // every live session idles, whichever backend holds its user and whichever client it is on
+        self.c_extensions.tick_sessions();
     }
     //Read Only : Dont Change
-     pub fn increase_day(&mut self) {
+     pub fn increase_day(&mut self) -> Result<(), DatabaseError> {
         println!("Calling increase day");
         //Resolve all signup requests
-        self.sync_database();
-        // Increment the day counter
-        *(self._day_counter) += 1;
-        // Validate active user sessions
-        self.validate_active_user_session();
+        for (username, e) in self.sync_database() {
+            println!("[Signup Error] Failed to sign up user {}: {}", username, e);
+        }
+        // Increment the day counter and validate active user sessions
+        self.log_and_apply(WalRecord::NewDay)?;
         // Update rust database (uses the function you translated for Part 1)
-        update_database_daily(&mut self.rust_db);
+        self.log_and_apply(WalRecord::Purge)?;
         // Every 5 days, join the two databases
         println!("calling join daataases");
-        if *(self._day_counter) % 5 == 0 {
-            self.join_databases();
+        if self.day() % 5 == 0 {
+            self.log_and_apply(WalRecord::Join)?;
         }
         // Perform daily updates on C backend
-        self.c_extensions.increment_day(&self.rust_db);
+        self.log_and_apply(WalRecord::Compaction)
+    }
+
+    // append to the log, when there is one, before the change takes effect
+    fn log(&mut self, record: &WalRecord) -> Result<(), DatabaseError> {
+        if let Some(wal) = &mut self.wal {
+            wal.append(self.applied_lsn + 1, record)?;
+        }
+        self.applied_lsn += 1;
+        Ok(())
+    }
+
+    fn log_and_apply(&mut self, record: WalRecord) -> Result<(), DatabaseError> {
+        self.log(&record)?;
+        self.apply(&record)
+    }
+
+    // shared by the live path and log replay, so both must end in the same state
+    fn apply(&mut self, record: &WalRecord) -> Result<(), DatabaseError> {
+        match record {
+            WalRecord::Signup { backend: Backend::Rust, user } => {
+                restore_user(&mut self.rust_db, Box::new(user.clone()))?;
+            }
+            WalRecord::Signup { backend: Backend::C, user } => {
+                self.c_extensions.restore_c_user(user)?;
+                self.c_allocated_users.push(user.user_id);
+            }
+            WalRecord::Login { username, token, client } => {
+                self.restore_login(username, token, client)?;
+                self.login_guard.record_success(username);
+            }
+            WalRecord::LoginFailed { username } => {
+                let day = self.day();
+                self.login_guard.record_failure(username, day);
+            }
+            WalRecord::NewDay => {
+                self.clock.borrow_mut().advance_day();
+                self.validate_active_user_session();
+            }
+            WalRecord::Purge => {
+                for user in update_database_daily(&mut self.rust_db) {
+                    if let Err(e) = self.c_extensions.release_rust_user(user) {
+                        println!("[Ownership Error] {}", e);
+                    }
+                }
+            }
+            WalRecord::Join => self.join_databases(),
+            WalRecord::Reconcile { source } => {
+                self.repair_backends(*source);
+            }
+            WalRecord::Rename { old, new } => {
+                rename_user(&mut self.rust_db, old, new)?;
+                self.c_extensions.rename_sessions(old, new);
+                self.login_guard.rename(old, new);
+            }
+            WalRecord::Compaction => {
+                self.c_extensions.increment_day(&mut self.rust_db);
+                self.update_c_originals();
+                // a cached handle may now be the last reference to a user C purged, dropping it frees the user
+                self.user_references.retain(|handle| !handle.is_released());
+            }
+            WalRecord::Logout { .. } | WalRecord::RevokeUser { .. } | WalRecord::RevokeBefore { .. } => {
+                self.apply_revocation(record);
+            }
+            WalRecord::Touch { token } => {
+                self.c_extensions.touch_session(token);
+            }
+            WalRecord::IssueRefresh { session_token, refresh_token } => {
+                let session = self
+                    .c_extensions
+                    .session(session_token)
+                    .cloned()
+                    .ok_or_else(|| DatabaseError::NotFound(format!("Session {}", session_token)))?;
+                self.c_extensions.issue_refresh(&session, refresh_token);
+            }
+            WalRecord::Refresh { refresh_token, token, new_refresh } => {
+                let grant = self
+                    .c_extensions
+                    .take_refresh(refresh_token)
+                    .ok_or_else(|| DatabaseError::NotFound("Refresh token".to_string()))?;
+                self.restore_login(&grant.username, token, &grant.client)?;
+                let session = self
+                    .c_extensions
+                    .session(token)
+                    .cloned()
+                    .ok_or_else(|| DatabaseError::NotFound(format!("Session {}", token)))?;
+                self.c_extensions.issue_refresh(&session, new_refresh);
+            }
+        }
+        Ok(())
+    }
+
+    pub fn set_session_lifetimes(&mut self, lifetimes: SessionLifetimes) {
+        self.c_extensions.set_session_lifetimes(lifetimes);
+    }
+
+    /// Opaque random tokens (the default) or HMAC-signed ones that `validate_session` checks
+    /// without the session table. Like the other settings this isn't persisted, set the same
+    /// key again after `open` to keep earlier signed tokens valid.
+    pub fn set_token_mode(&mut self, mode: TokenMode) {
+        self.c_extensions.set_token_mode(mode);
+    }
+
+    /// Sliding renewal, activity on a live session resets its idle time. Returns the status
+    /// after the renewal, sessions that aren't live are left alone.
+    pub fn touch_session(&mut self, token: &str) -> Result<SessionStatus, DatabaseError> {
+        if let SessionStatus::Valid { .. } = self.validate_session(token) {
+            self.log(&WalRecord::Touch { token: token.to_string() })?;
+            return Ok(self.c_extensions.touch_session(token));
+        }
+        Ok(self.validate_session(token))
+    }
+
+    /// A refresh token for the user and client of the live session `session_token`, replacing
+    /// any earlier one for that client. It outlives the session, see `refresh_session`.
+    pub fn issue_refresh_token(&mut self, session_token: &str) -> Result<String, DatabaseError> {
+        match self.validate_session(session_token) {
+            SessionStatus::Valid { .. } => {}
+            SessionStatus::Unknown => return Err(DatabaseError::NotFound(format!("Session {}", session_token))),
+            SessionStatus::Expired | SessionStatus::Revoked => return Err(DatabaseError::SessionExpired),
+        }
+        let refresh_token = self.c_extensions.generate_token()?;
+        self.log_and_apply(WalRecord::IssueRefresh {
+            session_token: session_token.to_string(),
+            refresh_token: refresh_token.clone(),
+        })?;
+        Ok(refresh_token)
+    }
+
+    /// Log in again without the password: trade a refresh token for a new session on the same
+    /// client. Returns the new session token and the refresh token that replaces the one used.
+    pub fn refresh_session(&mut self, refresh_token: &str) -> Result<(String, String), DatabaseError> {
+        let grant = self.c_extensions.refresh_grant(refresh_token)?;
+        if find_user_by_username(&self.rust_db, &grant.username).is_none()
+            && self.c_extensions.get_user_in_c_backend(&grant.username).is_null()
+        {
+            return Err(DatabaseError::NotFound(format!("User {}", grant.username)));
+        }
+        let token = self.c_extensions.generate_token()?;
+        let new_refresh = self.c_extensions.generate_token()?;
+        self.log_and_apply(WalRecord::Refresh {
+            refresh_token: refresh_token.to_string(),
+            token: token.clone(),
+            new_refresh: new_refresh.clone(),
+        })?;
+        Ok((token, new_refresh))
+    }
+
+    /// End the session holding `token` and its client's refresh token, its user has to log in again
+    pub fn logout(&mut self, token: &str) -> Result<(), DatabaseError> {
+        if !self.c_extensions.session(token).is_some_and(Session::is_active) {
+            return Err(DatabaseError::NotFound(format!("Session {}", token)));
+        }
+        self.revoke(WalRecord::Logout { token: token.to_string() })?;
+        Ok(())
+    }
+
+    /// End every live session and refresh token of `user_name`, returning how many sessions there were
+    pub fn revoke_all_sessions(&mut self, user_name: &str) -> Result<usize, DatabaseError> {
+        self.revoke(WalRecord::RevokeUser { username: user_name.to_string() })
+    }
+
+    /// End every live session and refresh token from before `day`, returning how many sessions there were
+    pub fn revoke_sessions_older_than(&mut self, day: i32) -> Result<usize, DatabaseError> {
+        self.revoke(WalRecord::RevokeBefore { day })
+    }
+
+    fn revoke(&mut self, record: WalRecord) -> Result<usize, DatabaseError> {
+        self.log(&record)?;
+        Ok(self.apply_revocation(&record))
+    }
+
+    /// The user's live sessions, one per client, oldest first
+    pub fn list_sessions(&self, user_name: &str) -> Vec<Session> {
+        self.c_extensions.sessions_of(user_name)
+    }
+
+    /// What `token` is worth right now, any one of a user's sessions resolves to the same
+    /// user. Read-only, sessions only age in the daily tick.
+    pub fn validate_session(&self, token: &str) -> SessionStatus {
+        self.c_extensions.validate_session(token)
+    }
+
+    // marks the sessions inactive and hands each user whose current token ended their newest
+    // remaining session, or clears it, in whichever backend owns the user
+    fn apply_revocation(&mut self, record: &WalRecord) -> usize {
+        let ended = match record {
+            WalRecord::Logout { token } => {
+                let ended = self.c_extensions.revoke_sessions(|session| &session.token == token);
+                for session in &ended {
+                    self.c_extensions.revoke_refresh_tokens(|grant| {
+                        grant.username == session.username && grant.client == session.client
+                    });
+                }
+                ended
+            }
+            WalRecord::RevokeUser { username } => {
+                self.c_extensions.revoke_refresh_tokens(|grant| &grant.username == username);
+                self.c_extensions.revoke_sessions(|session| &session.username == username)
+            }
+            WalRecord::RevokeBefore { day } => {
+                self.c_extensions.revoke_refresh_tokens(|grant| grant.issued_day < *day);
+                self.c_extensions.revoke_sessions(|session| session.created_day < *day)
+            }
+            _ => Vec::new(),
+        };
+        for session in &ended {
+            let newest = self
+                .c_extensions
+                .sessions_of(&session.username)
+                .pop()
+                .map(|live| live.token)
+                .unwrap_or_default();
+            match find_user_by_username_mut(&mut self.rust_db, &session.username) {
+                Some(user) => {
+                    if bytes_to_string(&user.session_token) == session.token {
+                        user.session_token = string_to_bytes(newest);
+                    }
+                }
+                None => self.c_extensions.replace_c_session_token(&session.username, &session.token, &newest),
+            }
+            self.session_tokens.retain(|token| token != &session.token);
+        }
+        ended.len()
+    }
+
+    // C stays responsible for its users after a join copies them into Rust, the copy keeps
+    // the C id
+    fn owner_of(&self, user_name: &str) -> Backend {
+        if self.c_extensions.holds_c_user(user_name) {
+            Backend::C
+        } else {
+            Backend::Rust
+        }
+    }
+
+    // login_user minus the password check and token minting
+    fn restore_login(&mut self, user_name: &str, token: &str, client: &str) -> Result<(), DatabaseError> {
+        let user_id = match find_user_by_username(&self.rust_db, user_name) {
+            Some(user) => user.user_id,
+            None => return self.c_extensions.restore_c_login(user_name, token, client),
+        };
+        let backend = self.owner_of(user_name);
+        self.c_extensions.restore_login(Session::new(user_id, backend, user_name, token, client, 0))?;
+        self.update_user_session_token(user_name, token.to_string());
+        self.activate_user(user_name);
+        Ok(())
+    }
+
+    /// Like `new`, but durable: starts from the snapshot at `snapshot_path` if there is one,
+    /// replays the write-ahead log at `wal_path` on top of it and logs every change from then on
+    pub fn open(snapshot_path: &Path, wal_path: &Path, clock: Box<dyn Clock>) -> Result<Self, DatabaseError> {
+        let mut db = if snapshot_path.exists() {
+            Self::load_snapshot(snapshot_path, clock)?
+        } else {
+            Self::with_clock(clock)
+        };
+        let (wal, records) = Wal::open(wal_path)?;
+        let mut replayed = 0;
+        for (lsn, record) in records {
+            // already in the snapshot, the log is only truncated after a checkpoint lands
+            if lsn <= db.applied_lsn {
+                continue;
+            }
+            db.applied_lsn = lsn;
+            replayed += 1;
+            // a change that failed when it was logged fails the same way again
+            if let Err(e) = db.apply(&record) {
+                println!("[WAL] Record {} not applied: {}", lsn, e);
+            }
+        }
+        println!("[WAL] Replayed {} records from {}", replayed, wal_path.display());
+        db.wal = Some(wal);
+        Ok(db)
+    }
+
+    /// Write both backends and the session table to `path`, replacing any previous snapshot,
+    /// then empty the write-ahead log. Pending signups and C's handles to shared Rust users
+    /// are not saved.
+    pub fn save_snapshot(&mut self, path: &Path) -> Result<(), DatabaseError> {
+        let snapshot = Snapshot {
+            day: self.day(),
+            wal_lsn: self.applied_lsn,
+            rust_users: self.rust_db.iter().cloned().collect(),
+            c_users: self.c_extensions.export_c_users(),
+            sessions: self.c_extensions.export_sessions(),
+            refresh_tokens: self.c_extensions.export_refresh_tokens(),
+            revoked_tokens: self.c_extensions.export_revoked_tokens(),
+            login_failures: self.login_guard.failures().cloned().collect(),
+        };
+        snapshot.save(path)?;
+        if let Some(wal) = &mut self.wal {
+            wal.truncate()?;
+        }
+        Ok(())
+    }
+
+    /// Rebuild a database from a snapshot written by `save_snapshot`
+    pub fn load_snapshot(path: &Path, clock: Box<dyn Clock>) -> Result<Self, DatabaseError> {
+        let snapshot = Snapshot::load(path)?;
+        let mut db = Self::with_clock(clock);
+        db.clock.borrow_mut().set_day(snapshot.day);
+        db.applied_lsn = snapshot.wal_lsn;
+        for user in snapshot.rust_users {
+            restore_user(&mut db.rust_db, Box::new(user))?;
+        }
+        for user in &snapshot.c_users {
+            db.c_extensions.restore_c_user(user)?;
+        }
+        for session in snapshot.sessions {
+            db.c_extensions.restore_session(session)?;
+        }
+        for grant in snapshot.refresh_tokens {
+            db.c_extensions.restore_refresh(grant);
+        }
+        for (token, expires_day, state) in &snapshot.revoked_tokens {
+            db.c_extensions.restore_revoked(token, *expires_day, *state);
+        }
+        for record in snapshot.login_failures {
+            db.login_guard.restore(record);
+        }
+        Ok(db)
+    }
+
+    /// Every user either backend owns, Rust users first. C's handles to shared Rust users, and
+    /// C users a join has copied into Rust, are left out so nobody is listed twice.
+    pub fn user_records(&self) -> Vec<UserRecord> {
+        let rust_users = self.rust_db.iter().map(|user| (Backend::Rust, user.clone()));
+        let c_users = self
+            .c_extensions
+            .export_c_users()
+            .into_iter()
+            .filter(|user| find_user_by_username(&self.rust_db, &bytes_to_string(&user.username)).is_none())
+            .map(|user| (Backend::C, user));
+        rust_users
+            .chain(c_users)
+            .map(|(backend, user)| UserRecord {
+                username: bytes_to_string(&user.username),
+                email: bytes_to_string(&user.email),
+                id: user.user_id,
+                inactivity: user.inactivity_count,
+                active: user.is_active != 0,
+                backend,
+                password: None,
+            })
+            .collect()
+    }
+
+    pub fn export_users<W: Write>(&self, format: UserFormat, out: W) -> Result<(), DatabaseError> {
+        user_io::write_users(format, &self.user_records(), out)
+    }
+
+    /// Import users read with `user_io::read_users` into the backend each row names. Every
+    /// row is checked against both backends and the rows before it before anything is
+    /// inserted, then hashed and checked for room in its backend. With `dry_run` every check
+    /// runs but nothing is inserted.
+    pub fn import_users(&mut self, records: &[UserRecord], dry_run: bool) -> ImportReport {
+        let mut report = ImportReport::default();
+        let mut names = HashSet::new();
+        let mut emails = HashSet::new();
+        // ids are only unique within a backend
+        let mut ids: HashSet<(Backend, i32)> = self
+            .user_records()
+            .iter()
+            .map(|user| (user.backend, user.id))
+            .collect();
+
+        let mut accepted = Vec::new();
+        for (i, record) in records.iter().enumerate() {
+            let checked = user_io::validate_record(record)
+                .and_then(|_| self.check_signup_conflict(&record.username, &record.email))
+                .and_then(|_| {
+                    if !names.insert(record.username.clone()) {
+                        return Err(DatabaseError::Duplicate { field: "username", value: record.username.clone() });
+                    }
+                    if self.unique_emails && !emails.insert(record.email.clone()) {
+                        return Err(DatabaseError::Duplicate { field: "email", value: record.email.clone() });
+                    }
+                    if !ids.insert((record.backend, record.id)) {
+                        return Err(DatabaseError::Duplicate { field: "id", value: record.id.to_string() });
+                    }
+                    Ok(())
+                });
+            match checked {
+                Ok(()) => accepted.push((i + 1, record)),
+                Err(e) => report.rejected.push((i + 1, record.username.clone(), e)),
+            }
+        }
+
+        let mut c_free_slots = self.c_extensions.free_slots();
+        for (row, record) in accepted {
+            let imported = self.imported_user(record).and_then(|(user, generated)| {
+                if record.backend == Backend::C {
+                    // the rows before it take their slots even on a dry run
+                    c_free_slots = c_free_slots.checked_sub(1).ok_or(DatabaseError::Full)?;
+                }
+                if !dry_run {
+                    self.log_and_apply(WalRecord::Signup { backend: record.backend, user })?;
+                    if let Some(password) = generated {
+                        report.generated_passwords.push((record.username.clone(), password));
+                    }
+                }
+                Ok(())
+            });
+            match imported {
+                Ok(()) => report.imported.push(record.username.clone()),
+                Err(e) => report.rejected.push((row, record.username.clone(), e)),
+            }
+        }
+        report.rejected.sort_by_key(|(row, _, _)| *row);
+        report
+    }
+
+    pub fn import_users_from<R: Read>(
+        &mut self,
+        format: UserFormat,
+        input: R,
+        dry_run: bool,
+    ) -> Result<ImportReport, DatabaseError> {
+        let records = user_io::read_users(format, input)?;
+        Ok(self.import_users(&records, dry_run))
+    }
+
+    // hashed the way a signup to the same backend would be, with the password generated for
+    // a row that had none
+    fn imported_user(&self, record: &UserRecord) -> Result<(UserStruct, Option<String>), DatabaseError> {
+        let (password, generated) = match &record.password {
+            Some(password) => {
+                self.password_policy.check(password)?;
+                (password.clone(), None)
+            }
+            None => {
+                let mut random = [0u8; 16];
+                getrandom::getrandom(&mut random)
+                    .map_err(|e| DatabaseError::BackendFailure(format!("no randomness for a password: {}", e)))?;
+                let password: String = random.iter().map(|b| format!("{:02x}", b)).collect();
+                (password.clone(), Some(password))
+            }
+        };
+        let mut user = match record.backend {
+            Backend::Rust => *create_user(&record.username, &record.email, record.id, &password)?,
+            Backend::C => self.c_extensions.prepare_c_user(&record.username, &record.email, &password)?,
+        };
+        user.user_id = record.id;
+        user.inactivity_count = record.inactivity;
+        user.is_active = record.active as i32;
+        Ok((user, generated))
+    }
+
+    /// Every session in the table, live or not, in table order
+    pub fn session_inventory(&self) -> Vec<SessionInfo> {
+        self.c_extensions
+            .export_sessions()
+            .iter()
+            .map(SessionInfo::new)
+            .collect()
+    }
+
+    /// Live sessions per user, for users that have any
+    pub fn session_counts(&self) -> BTreeMap<String, usize> {
+        let mut counts = BTreeMap::new();
+        for session in self.c_extensions.export_sessions().iter().filter(|session| session.is_active()) {
+            *counts.entry(session.username.clone()).or_insert(0) += 1;
+        }
+        counts
+    }
+
+    pub fn print_sessions(&self) {
+        let inventory = self.session_inventory();
+        let live = inventory.iter().filter(|info| info.state == SessionState::Active).count();
+        println!("[Sessions] Day {}: {} sessions, {} live", self.day(), inventory.len(), live);
+        for info in &inventory {
+            println!(
+                "  {} (ID: {}, {:?}) client={} token={}... created day {} idle {} {:?}",
+                info.username,
+                info.user_id,
+                info.backend,
+                info.client,
+                info.token_prefix,
+                info.created_day,
+                info.idle_time,
+                info.state
+            );
+        }
+        for (username, count) in self.session_counts() {
+            if count > 1 {
+                println!("  {} has {} live sessions", username, count);
+            }
+        }
     }
 
     pub fn print_both_databases(&self) {
@@ -298,6 +1146,13 @@ impl EnhancedStudentDatabase {
         database_fix_full::print_database(&self.rust_db);
     }
 }
+impl Default for EnhancedStudentDatabase {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+#[allow(dead_code)]
 fn create_small_test_data() -> Vec<DayData> {
     vec![
         DayData {
@@ -323,20 +1178,41 @@ fn create_small_test_data() -> Vec<DayData> {
 fn main() {
     println!("=======Mixed Code Student Database System========");
 
-    let mut db = EnhancedStudentDatabase::new();
+    // optional snapshot and write-ahead log paths: resume from them if they exist, checkpoint at the end
+    let args: Vec<String> = std::env::args().collect();
+    let snapshot_path = args.get(1).map(Path::new);
+    let wal_path = args.get(2).map(Path::new);
+    let clock: Box<dyn Clock> = Box::<ManualClock>::default();
+    let resumed = match (snapshot_path, wal_path) {
+        (Some(snapshot), Some(wal)) => EnhancedStudentDatabase::open(snapshot, wal, clock),
+        (Some(snapshot), None) if snapshot.exists() => EnhancedStudentDatabase::load_snapshot(snapshot, clock),
+        _ => Ok(EnhancedStudentDatabase::with_clock(clock)),
+    };
+    let mut db = match resumed {
+        Ok(db) => db,
+        Err(e) => {
+            println!("[Recovery Error] Failed to restore the database: {}", e);
+            return;
+        }
+    };
+    if db.day() > 0 {
+        println!("[Info] Resumed at day {}", db.day());
+    }
     println!("mixed: database created");
     println!("About to test simple Vec creation...");
     // let test_vec: Vec<i32> = vec![1, 2, 3];
     // println!("Simple Vec created: {:?}", test_vec);
 
     // Initialize with static data
-    // try moving into a box to move into heap
     // let days_data = create_small_test_data();
-    let days_data = Box::new(generated_data::get_days_data());
+    let days_data = generated_data::get_days_data();
 
     println!("Days created");
     // Process each day's activities
     for day_data in days_data.iter() {
+        if day_data.day <= db.day() {
+            continue;
+        }
         let mut local_session_tokens: Vec<String> = Vec::new();
 
         println!("============================[Info] Processing day {}===========================", day_data.day);
@@ -368,10 +1244,8 @@ fn main() {
         if let Some(logins) = &day_data.logins {
             println!("=========[Info] Processing Logins============");
             for login in logins {
-                let password =  login.password.clone().chars().take(MAX_PASSWORD_LENGTH-1).collect::<String>();
-
                 // Attempt user login
-                match db.login_user(&login.username, &password) {
+                match db.login_user(&login.username, &login.password) {
                     Ok(session_token) => {
                         println!("[Login] User {} logged in successfully", login.username);
                         local_session_tokens.push(session_token);
@@ -385,8 +1259,22 @@ fn main() {
                 }
             }
         }
+        db.print_sessions();
         println!("========[Info] Performing end-of-day updates========");
-        db.increase_day();
+        if let Err(e) = db.increase_day() {
+            println!("[Day Error] End-of-day update failed: {}", e);
+        }
+        if let Some(report) = db.last_join_report().filter(|report| report.day == db.day()) {
+            println!(
+                "[Join] Day {} under {:?}: shared {}, imported {}, {} conflicts{}",
+                report.day,
+                report.policy,
+                report.shared,
+                report.imported,
+                report.conflicts.len(),
+                if report.aborted { ", join skipped" } else { "" }
+            );
+        }
 
         println!(
             "=====[Info Day {}] Total Site traffic on Rust DB = {}======",
@@ -398,6 +1286,444 @@ fn main() {
     println!("\n====================Congratulations! End of Simulation====================\n");
 
     db.print_both_databases();
+
+    match db.reconcile(None) {
+        Ok(report) => {
+            for finding in &report.findings {
+                println!("[Reconcile] {}", finding.discrepancy);
+            }
+            println!(
+                "[Reconcile] Day {}: {} discrepancies, {} repaired, consistent: {}",
+                report.day,
+                report.findings.len(),
+                report.repaired(),
+                report.is_consistent()
+            );
+        }
+        Err(e) => println!("[Reconcile Error] {}", e),
+    }
+
+    if let Some(path) = snapshot_path {
+        match db.save_snapshot(path) {
+            Ok(()) => println!("[Info] Saved snapshot to {}", path.display()),
+            Err(e) => println!("[Snapshot Error] Failed to save {}: {}", path.display(), e),
+        }
+    }
     
     println!("\n==========================Did you really fix it ?======================================\n");
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::path::PathBuf;
+    use std::sync::{Mutex, MutexGuard};
+
+    // C keeps its clock in a static, so only one database may be alive at a time
+    static C_BACKEND: Mutex<()> = Mutex::new(());
+
+    fn c_backend() -> MutexGuard<'static, ()> {
+        C_BACKEND.lock().unwrap_or_else(|poisoned| poisoned.into_inner())
+    }
+
+    fn temp_path(name: &str) -> PathBuf {
+        let path = std::env::temp_dir().join(format!("esdb-test-{}-{}", name, std::process::id()));
+        let _ = std::fs::remove_file(&path);
+        path
+    }
+
+    fn clock() -> Box<dyn Clock> {
+        Box::<ManualClock>::default()
+    }
+
+    // alice signs up to Rust and bob to C, a day passes and alice logs in
+    fn populate(db: &mut EnhancedStudentDatabase) -> String {
+        db.add_user_with_sync("alice", "alice@example.com", "secret", 1).unwrap();
+        db.add_user_with_sync("bob", "bob@example.com", "hunter2", 6).unwrap();
+        db.increase_day().unwrap();
+        db.login_user("alice", "secret").unwrap()
+    }
+
+    type UserRow = (String, String, i32, i32, bool, Backend);
+
+    fn state(db: &EnhancedStudentDatabase) -> (i32, Vec<UserRow>, Vec<SessionInfo>) {
+        let users = db
+            .user_records()
+            .into_iter()
+            .map(|user| (user.username, user.email, user.id, user.inactivity, user.active, user.backend))
+            .collect();
+        (db.day(), users, db.session_inventory())
+    }
+
+    #[test]
+    fn snapshot_round_trip() {
+        let _c = c_backend();
+        let path = temp_path("round-trip.snap");
+        let mut db = EnhancedStudentDatabase::with_clock(clock());
+        let token = populate(&mut db);
+        db.save_snapshot(&path).unwrap();
+        let saved = state(&db);
+        drop(db);
+
+        let loaded = EnhancedStudentDatabase::load_snapshot(&path, clock());
+        let _ = std::fs::remove_file(&path);
+        let mut loaded = loaded.unwrap();
+        assert_eq!(state(&loaded), saved);
+        assert_eq!(loaded.day(), 1);
+        assert!(matches!(loaded.validate_session(&token), SessionStatus::Valid { .. }));
+        // hashes survive, both users can still log in
+        loaded.login_user("alice", "secret").unwrap();
+        loaded.login_user("bob", "hunter2").unwrap();
+    }
+
+    #[test]
+    fn wal_replays_changes_since_the_snapshot() {
+        let _c = c_backend();
+        let snapshot_path = temp_path("replay.snap");
+        let wal_path = temp_path("replay.wal");
+        let mut db = EnhancedStudentDatabase::open(&snapshot_path, &wal_path, clock()).unwrap();
+        let token = populate(&mut db);
+        db.save_snapshot(&snapshot_path).unwrap();
+        // only in the log
+        db.logout(&token).unwrap();
+        db.add_user_with_sync("carol", "carol@example.com", "pa55word", 1).unwrap();
+        db.increase_day().unwrap();
+        let logged = state(&db);
+        let status = db.validate_session(&token);
+        drop(db);
+
+        let reopened = EnhancedStudentDatabase::open(&snapshot_path, &wal_path, clock());
+        let _ = std::fs::remove_file(&snapshot_path);
+        let _ = std::fs::remove_file(&wal_path);
+        let reopened = reopened.unwrap();
+        assert_eq!(state(&reopened), logged);
+        assert_eq!(reopened.day(), 2);
+        assert!(!matches!(status, SessionStatus::Valid { .. }));
+        assert_eq!(reopened.validate_session(&token), status);
+    }
+
+    // after a join, dave signs up to C and erin to Rust, and bob's Rust copy drifts
+    fn seed_mismatches(db: &mut EnhancedStudentDatabase) {
+        db.add_user_with_sync("dave", "dave@example.com", "letmein1", 6).unwrap();
+        db.add_user_with_sync("erin", "erin@example.com", "letmein2", 1).unwrap();
+        let bob = find_user_by_username_mut(&mut db.rust_db, "bob").unwrap();
+        database_fix_full::copy_string(&mut bob.email, "bob@elsewhere.example");
+        bob.inactivity_count += 3;
+    }
+
+    fn joined() -> EnhancedStudentDatabase {
+        let mut db = EnhancedStudentDatabase::with_clock(clock());
+        populate(&mut db);
+        db.join_databases();
+        db
+    }
+
+    #[test]
+    fn join_leaves_the_backends_consistent() {
+        let _c = c_backend();
+        let mut db = joined();
+        let report = db.reconcile(None).unwrap();
+        assert!(report.findings.is_empty(), "{:?}", report.findings);
+        assert!(report.is_consistent());
+    }
+
+    #[test]
+    fn repair_clears_seeded_mismatches() {
+        let _c = c_backend();
+        for source in [Backend::Rust, Backend::C] {
+            let mut db = joined();
+            seed_mismatches(&mut db);
+            let check = db.reconcile(None).unwrap();
+            assert!(!check.is_consistent());
+            let missing = |from| {
+                check.findings.iter().any(|finding| {
+                    matches!(&finding.discrepancy, Discrepancy::Missing { from: f, .. } if *f == from)
+                })
+            };
+            assert!(missing(Backend::Rust) && missing(Backend::C));
+            assert!(check.findings.iter().any(|finding| finding.discrepancy.username() == "bob"));
+
+            let repair = db.reconcile(Some(source)).unwrap();
+            assert_eq!(repair.source_of_truth, Some(source));
+            assert_eq!(repair.findings.len(), check.findings.len());
+            assert!(repair.is_consistent(), "{:?}", repair.findings);
+            assert!(db.reconcile(None).unwrap().findings.is_empty());
+            let bob = find_user_by_username(&db.rust_db, "bob").unwrap();
+            let expected = match source {
+                Backend::Rust => "bob@elsewhere.example",
+                Backend::C => "bob@example.com",
+            };
+            assert_eq!(bytes_to_string(&bob.email), expected);
+        }
+    }
+
+    #[test]
+    fn c_user_handle_outlives_the_database() {
+        let _c = c_backend();
+        let mut db = EnhancedStudentDatabase::with_clock(clock());
+        populate(&mut db);
+        let handle = db.c_extensions.c_user_handle("bob").unwrap().unwrap();
+        assert_eq!(handle.email().unwrap(), "bob@example.com");
+        drop(db);
+        // the table is gone, the handle holds the last reference and frees bob as it drops
+        assert!(handle.is_released());
+        assert!(matches!(handle.email(), Err(DatabaseError::NotFound(_))));
+        drop(handle);
+    }
+
+    #[test]
+    fn logins_replay_from_the_log_alone() {
+        let _c = c_backend();
+        let snapshot_path = temp_path("logins.snap");
+        let wal_path = temp_path("logins.wal");
+        let mut db = EnhancedStudentDatabase::open(&snapshot_path, &wal_path, clock()).unwrap();
+        let alice = populate(&mut db);
+        let bob = db.login_user("bob", "hunter2").unwrap();
+        let logged = state(&db);
+        drop(db);
+
+        let reopened = EnhancedStudentDatabase::open(&snapshot_path, &wal_path, clock());
+        let _ = std::fs::remove_file(&wal_path);
+        let reopened = reopened.unwrap();
+        assert_eq!(state(&reopened), logged);
+        for token in [alice, bob] {
+            assert!(matches!(reopened.validate_session(&token), SessionStatus::Valid { .. }));
+        }
+    }
+
+    #[test]
+    fn rename_replays_before_the_logins_under_the_new_name() {
+        let _c = c_backend();
+        let snapshot_path = temp_path("rename.snap");
+        let wal_path = temp_path("rename.wal");
+        let mut db = EnhancedStudentDatabase::open(&snapshot_path, &wal_path, clock()).unwrap();
+        populate(&mut db);
+        db.rename_user("alice", "alicia").unwrap();
+        let token = db.login_user("alicia", "secret").unwrap();
+        let logged = state(&db);
+        drop(db);
+
+        let reopened = EnhancedStudentDatabase::open(&snapshot_path, &wal_path, clock());
+        let _ = std::fs::remove_file(&wal_path);
+        let reopened = reopened.unwrap();
+        assert_eq!(state(&reopened), logged);
+        assert!(find_user_by_username(&reopened.rust_db, "alice").is_none());
+        assert!(matches!(reopened.validate_session(&token), SessionStatus::Valid { .. }));
+    }
+
+    #[test]
+    fn signup_rejects_names_the_backends_would_cut_short() {
+        let _c = c_backend();
+        let mut db = EnhancedStudentDatabase::with_clock(clock());
+        let stored = "n".repeat(database_fix_full::MAX_NAME_LEN - 1);
+        db.add_user_with_sync(&stored, "first@example.com", "secret", 1).unwrap();
+        // cut to MAX_NAME_LEN - 1 bytes it would be `stored`, in either backend
+        let longer: &'static str = format!("{}-second", stored).leak();
+        for pending_count in [1, 6] {
+            let err = db.add_user_with_sync(longer, "second@example.com", "secret", pending_count);
+            assert!(matches!(err, Err(DatabaseError::InvalidInput(_))), "{:?}", err);
+        }
+        let err = db.enqueue_user(longer, "second@example.com", "secret");
+        assert!(matches!(err, Err(DatabaseError::InvalidInput(_))), "{:?}", err);
+        let long_email: &'static str = format!("{}@example.com", "e".repeat(database_fix_full::MAX_EMAIL_LEN)).leak();
+        let err = db.enqueue_user("carol", long_email, "secret");
+        assert!(matches!(err, Err(DatabaseError::InvalidInput(_))), "{:?}", err);
+        assert_eq!(db.user_records().len(), 1);
+    }
+
+    #[test]
+    fn rename_carries_sessions_refresh_tokens_and_lockout() {
+        let _c = c_backend();
+        let mut db = EnhancedStudentDatabase::with_clock(clock());
+        db.set_lockout_policy(LockoutPolicy { max_failures: 2, ..LockoutPolicy::default() });
+        let token = populate(&mut db);
+        let refresh = db.issue_refresh_token(&token).unwrap();
+        for _ in 0..2 {
+            assert_eq!(db.login_user("alice", "wrong"), Err(DatabaseError::IncorrectPassword));
+        }
+
+        let too_long = "n".repeat(database_fix_full::MAX_NAME_LEN);
+        assert!(matches!(db.rename_user("alice", &too_long), Err(DatabaseError::InvalidInput(_))));
+        db.rename_user("alice", "alicia").unwrap();
+        assert!(matches!(db.login_user("alicia", "secret"), Err(DatabaseError::AccountLocked { .. })));
+        assert_eq!(db.list_sessions("alicia").len(), 1);
+        let (refreshed, _) = db.refresh_session(&refresh).unwrap();
+        assert_eq!(db.c_extensions.session(&refreshed).unwrap().username, "alicia");
+        assert_eq!(db.revoke_all_sessions("alicia").unwrap(), 1);
+        assert!(db.list_sessions("alice").is_empty());
+    }
+
+    #[test]
+    fn sessions_of_c_users_idle_out_too() {
+        let _c = c_backend();
+        let mut db = EnhancedStudentDatabase::with_clock(clock());
+        db.set_session_lifetimes(SessionLifetimes { max_idle: 1, max_age_days: 100, ..SessionLifetimes::default() });
+        let alice = populate(&mut db);
+        let bob = db.login_user("bob", "hunter2").unwrap();
+        for _ in 0..2 {
+            db.increase_day().unwrap();
+            for token in [&alice, &bob] {
+                assert!(matches!(db.validate_session(token), SessionStatus::Valid { .. }));
+            }
+        }
+        db.increase_day().unwrap();
+        for token in [&alice, &bob] {
+            assert!(!matches!(db.validate_session(token), SessionStatus::Valid { .. }));
+        }
+    }
+
+    #[test]
+    fn signed_tokens_say_which_backend_their_user_id_is_from() {
+        let _c = c_backend();
+        let mut db = EnhancedStudentDatabase::with_clock(clock());
+        db.set_token_mode(TokenMode::Signed { key: [3; signed_token::SIGNING_KEY_LEN] });
+        let alice = populate(&mut db);
+        let bob = db.login_user("bob", "hunter2").unwrap();
+        let status = |token: &str| match db.validate_session(token) {
+            SessionStatus::Valid { user_id, backend, .. } => (user_id, backend),
+            other => panic!("{:?}", other),
+        };
+        let (alice_id, alice_backend) = status(&alice);
+        let (bob_id, bob_backend) = status(&bob);
+        // both are the first signup of their backend
+        assert_eq!(alice_id, bob_id);
+        assert_eq!((alice_backend, bob_backend), (Backend::Rust, Backend::C));
+        // still fits a user's session_token with its NUL
+        assert!(alice.len() < SESSION_TOKEN_MAX_LEN);
+    }
+
+    #[test]
+    fn inventory_reports_c_users_as_c_after_a_join() {
+        let _c = c_backend();
+        let mut db = joined();
+        // bob now has a Rust copy, C is still responsible for him
+        assert!(find_user_by_username(&db.rust_db, "bob").is_some());
+        db.login_user("bob", "hunter2").unwrap();
+        let backends: Vec<(String, Backend)> =
+            db.session_inventory().into_iter().map(|info| (info.username, info.backend)).collect();
+        assert!(backends.contains(&("alice".to_string(), Backend::Rust)));
+        assert!(backends.contains(&("bob".to_string(), Backend::C)));
+    }
+
+    // carol signs up to Rust and alice logs in again, then C gets an alice of its own that
+    // has been idle for less
+    fn conflicting(policy: JoinPolicy) -> EnhancedStudentDatabase {
+        let mut db = EnhancedStudentDatabase::with_clock(clock());
+        populate(&mut db);
+        db.add_user_with_sync("carol", "carol@example.com", "pa55word", 1).unwrap();
+        db.increase_day().unwrap();
+        db.login_user("alice", "secret").unwrap();
+        find_user_by_username_mut(&mut db.rust_db, "alice").unwrap().inactivity_count = 3;
+        let mut other = *database_fix_full::create_user("alice", "alice@c.example", 40, "other").unwrap();
+        other.inactivity_count = 1;
+        db.c_extensions.restore_c_user(&other).unwrap();
+        db.set_join_policy(policy);
+        db.join_databases();
+        db
+    }
+
+    #[test]
+    fn join_policies_settle_a_same_name_conflict() {
+        let _c = c_backend();
+        // Rust's alice has a session, C's is less inactive
+        for (policy, winner) in [
+            (JoinPolicy::PreferRust, Backend::Rust),
+            (JoinPolicy::PreferC, Backend::C),
+            (JoinPolicy::MostRecentlyActive, Backend::Rust),
+            (JoinPolicy::LowestInactivity, Backend::C),
+        ] {
+            let db = conflicting(policy);
+            let report = db.last_join_report().unwrap().clone();
+            assert_eq!(report.policy, policy);
+            assert!(!report.aborted);
+            assert_eq!(report.conflicts.len(), 1);
+            assert_eq!(report.conflicts[0].username, "alice");
+            assert_eq!(report.conflicts[0].winner, Some(winner), "{:?}", policy);
+            assert_eq!(report.conflicts[0].rust.inactivity, 3);
+            assert_eq!(report.conflicts[0].c.inactivity, 1);
+            // carol is newly listed in C, bob is copied into Rust, alice is no longer a conflict
+            assert_eq!((report.shared, report.imported), (1, 1), "{:?}", policy);
+            let expected = match winner {
+                Backend::Rust => "alice@example.com",
+                Backend::C => "alice@c.example",
+            };
+            let rust_alice = find_user_by_username(&db.rust_db, "alice").unwrap();
+            assert_eq!(bytes_to_string(&rust_alice.email), expected, "{:?}", policy);
+            let c_alice = db.c_extensions.get_user_in_c_backend("alice");
+            assert_eq!(bytes_to_string(unsafe { &(*c_alice).email }), expected, "{:?}", policy);
+        }
+    }
+
+    #[test]
+    fn fail_on_conflict_stops_the_join() {
+        let _c = c_backend();
+        let db = conflicting(JoinPolicy::FailOnConflict);
+        let report = db.last_join_report().unwrap();
+        assert!(report.aborted);
+        assert_eq!(report.conflicts.len(), 1);
+        assert_eq!(report.conflicts[0].winner, None);
+        assert_eq!((report.shared, report.imported), (0, 0));
+        // neither alice was touched and bob was not imported
+        let rust_alice = find_user_by_username(&db.rust_db, "alice").unwrap();
+        assert_eq!(bytes_to_string(&rust_alice.email), "alice@example.com");
+        let c_alice = db.c_extensions.get_user_in_c_backend("alice");
+        assert_eq!(bytes_to_string(unsafe { &(*c_alice).email }), "alice@c.example");
+        assert!(find_user_by_username(&db.rust_db, "bob").is_none());
+    }
+
+    fn import_row(username: &str, backend: Backend, password: Option<&str>) -> UserRecord {
+        UserRecord {
+            username: username.to_string(),
+            email: format!("{}@example.com", username),
+            id: 40,
+            inactivity: 0,
+            active: false,
+            backend,
+            password: password.map(str::to_string),
+        }
+    }
+
+    #[test]
+    fn dry_run_import_changes_nothing() {
+        let _c = c_backend();
+        let mut db = EnhancedStudentDatabase::with_clock(clock());
+        populate(&mut db);
+        let before = state(&db);
+        let rows = [import_row("carol", Backend::Rust, None), import_row("dave", Backend::C, Some("letmein1"))];
+        let report = db.import_users(&rows, true);
+        assert_eq!(report.imported, ["carol", "dave"]);
+        assert!(report.rejected.is_empty());
+        assert!(report.generated_passwords.is_empty());
+        assert_eq!(state(&db), before);
+        assert!(db.c_extensions.get_user_in_c_backend("dave").is_null());
+
+        // the same rows then go in for real
+        let report = db.import_users(&rows, false);
+        assert_eq!(report.imported, ["carol", "dave"]);
+        assert_eq!(report.generated_passwords.len(), 1);
+        assert!(find_user_by_username(&db.rust_db, "carol").is_some());
+        assert!(!db.c_extensions.get_user_in_c_backend("dave").is_null());
+    }
+
+    #[test]
+    fn import_rejections_come_back_in_row_order() {
+        let _c = c_backend();
+        let mut db = EnhancedStudentDatabase::with_clock(clock());
+        populate(&mut db);
+        let rows = [
+            import_row("carol", Backend::Rust, None),
+            // fails the password policy, only checked once every row has been looked at
+            import_row("dave", Backend::C, Some("")),
+            // fail the checks against the backends and the rows before
+            import_row("alice", Backend::Rust, None),
+            import_row("carol", Backend::C, None),
+            UserRecord { id: 0, ..import_row("erin", Backend::Rust, None) },
+        ];
+        let report = db.import_users(&rows, false);
+        assert_eq!(report.imported, ["carol"]);
+        let rejected: Vec<(usize, &str)> =
+            report.rejected.iter().map(|(row, username, _)| (*row, username.as_str())).collect();
+        assert_eq!(rejected, [(2, "dave"), (3, "alice"), (4, "carol"), (5, "erin")]);
+        assert!(matches!(report.rejected[1].2, DatabaseError::Duplicate { field: "username", .. }));
+    }
+}
@@ -0,0 +1,227 @@
+// Who may free a user both backends can reach. The C side keeps OwnershipType and ref_count in
+// the user itself, every Rust call that moves a user across the FFI goes through hand_off so a
+// move the state machine doesn't allow is refused and reported instead of leaving a dangling
+// or doubly freed user behind.
+//
+//   RustOwned         --Share{to: C}-->    SharedRustPrimary   ref_count + 1
+//   COwned            --Share{to: Rust}--> SharedCPrimary      ref_count + 1
+//   Shared*           --Share-->           unchanged           ref_count + 1
+//   Shared*           --Release-->         unchanged           ref_count - 1, never the last
+//   any               --Free-->            gone                primary only, with the last reference
+use std::fmt;
+
+use crate::database_fix_full::{byte_to_string, DatabaseError, OwnershipType, UserStruct};
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Side {
+    Rust,
+    C,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Transfer {
+    // `to` takes a reference to a user the other side is responsible for
+    Share { to: Side },
+    // `by` drops a reference it took with Share
+    Release { by: Side },
+    // `by` frees the user
+    Free { by: Side },
+}
+
+impl fmt::Display for Transfer {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Transfer::Share { to } => write!(f, "sharing with {:?}", to),
+            Transfer::Release { by } => write!(f, "release by {:?}", by),
+            Transfer::Free { by } => write!(f, "free by {:?}", by),
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Ownership {
+    pub kind: OwnershipType,
+    pub ref_count: i32,
+}
+
+impl Ownership {
+    /// The state `user` is in, Err if it holds a value OwnershipType doesn't have or a
+    /// negative count
+    pub fn of(user: &UserStruct) -> Result<Self, DatabaseError> {
+        let kind = OwnershipType::try_from(user.ownership)
+            .map_err(|value| violation(user, format!("unknown ownership {}", value)))?;
+        if user.ref_count < 0 {
+            return Err(violation(user, format!("negative ref_count {}", user.ref_count)));
+        }
+        Ok(Ownership { kind, ref_count: user.ref_count })
+    }
+
+    /// The side responsible for freeing the user
+    pub fn primary(&self) -> Side {
+        match self.kind {
+            OwnershipType::RustOwned | OwnershipType::SharedRustPrimary => Side::Rust,
+            OwnershipType::COwned | OwnershipType::SharedCPrimary => Side::C,
+        }
+    }
+
+    /// The state `transfer` leaves the user in, None after a Free. Err(why) if the transfer
+    /// isn't allowed from this state.
+    pub fn after(self, transfer: Transfer) -> Result<Option<Self>, String> {
+        let primary = self.primary();
+        let shared = matches!(self.kind, OwnershipType::SharedRustPrimary | OwnershipType::SharedCPrimary);
+        match transfer {
+            Transfer::Share { to } if to == primary => Err(format!("{:?} already owns it", to)),
+            Transfer::Share { .. } => Ok(Some(Ownership {
+                kind: match primary {
+                    Side::Rust => OwnershipType::SharedRustPrimary,
+                    Side::C => OwnershipType::SharedCPrimary,
+                },
+                ref_count: self.ref_count + 1,
+            })),
+            Transfer::Release { by } if by == primary || !shared => {
+                Err(format!("{:?} holds no shared reference, it has to free it", by))
+            }
+            Transfer::Release { .. } if self.ref_count <= 1 => {
+                Err(format!("ref_count {} would drop the owner's reference", self.ref_count))
+            }
+            Transfer::Release { .. } => Ok(Some(Ownership { ref_count: self.ref_count - 1, ..self })),
+            Transfer::Free { by } if by != primary => Err(format!("it is {:?}, only {:?} frees it", self.kind, primary)),
+            Transfer::Free { .. } if self.ref_count > 1 => {
+                Err(format!("{} references are still held", self.ref_count))
+            }
+            Transfer::Free { .. } => Ok(None),
+        }
+    }
+}
+
+fn violation(user: &UserStruct, why: String) -> DatabaseError {
+    DatabaseError::OwnershipViolation(format!("user {}: {}", byte_to_string(&user.username), why))
+}
+
+/// Moves `user` through `transfers`: checks they are allowed from the state the user is in,
+/// makes the FFI call `ffi` that carries them out, then checks the user ended up where the
+/// state machine says. Nothing is called if a transfer is refused.
+///
+/// # Safety
+/// `user` must point to a live user, which stays live through `ffi` unless `transfers` ends
+/// with a Free.
+pub unsafe fn hand_off(user: *mut UserStruct, transfers: &[Transfer], ffi: impl FnOnce()) -> Result<(), DatabaseError> {
+    let mut expected = Some(Ownership::of(&*user)?);
+    for &transfer in transfers {
+        let state = expected.ok_or_else(|| violation(&*user, format!("{} after it was freed", transfer)))?;
+        expected = state.after(transfer).map_err(|why| violation(&*user, format!("{} refused, {}", transfer, why)))?;
+    }
+    ffi();
+    // a freed user can't be looked at any more
+    if let Some(expected) = expected {
+        let actual = Ownership::of(&*user)?;
+        if actual != expected {
+            return Err(violation(&*user, format!("left as {:?}, expected {:?}", actual, expected)));
+        }
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::database_fix_full::copy_string;
+
+    fn user(kind: OwnershipType, ref_count: i32) -> UserStruct {
+        let mut user = UserStruct { ownership: kind as i32, ref_count, ..UserStruct::default() };
+        copy_string(&mut user.username, "alice");
+        user
+    }
+
+    fn state(kind: OwnershipType, ref_count: i32) -> Ownership {
+        Ownership { kind, ref_count }
+    }
+
+    #[test]
+    fn allowed_transfers() {
+        use OwnershipType::*;
+        let cases = [
+            (state(RustOwned, 1), Transfer::Share { to: Side::C }, Some(state(SharedRustPrimary, 2))),
+            (state(COwned, 1), Transfer::Share { to: Side::Rust }, Some(state(SharedCPrimary, 2))),
+            (state(SharedCPrimary, 2), Transfer::Share { to: Side::Rust }, Some(state(SharedCPrimary, 3))),
+            (state(SharedRustPrimary, 2), Transfer::Release { by: Side::C }, Some(state(SharedRustPrimary, 1))),
+            (state(SharedCPrimary, 3), Transfer::Release { by: Side::Rust }, Some(state(SharedCPrimary, 2))),
+            (state(RustOwned, 1), Transfer::Free { by: Side::Rust }, None),
+            (state(SharedCPrimary, 1), Transfer::Free { by: Side::C }, None),
+        ];
+        for (from, transfer, to) in cases {
+            assert_eq!(from.after(transfer), Ok(to), "{:?} {}", from, transfer);
+        }
+    }
+
+    #[test]
+    fn refused_transfers() {
+        use OwnershipType::*;
+        let cases = [
+            // sharing with the primary
+            (state(RustOwned, 1), Transfer::Share { to: Side::Rust }),
+            (state(SharedCPrimary, 2), Transfer::Share { to: Side::C }),
+            // the primary frees, it doesn't release
+            (state(SharedRustPrimary, 2), Transfer::Release { by: Side::Rust }),
+            // nothing was shared
+            (state(COwned, 2), Transfer::Release { by: Side::Rust }),
+            // the owner's own reference
+            (state(SharedCPrimary, 1), Transfer::Release { by: Side::Rust }),
+            (state(RustOwned, 1), Transfer::Free { by: Side::C }),
+            (state(SharedCPrimary, 1), Transfer::Free { by: Side::Rust }),
+            (state(SharedRustPrimary, 2), Transfer::Free { by: Side::Rust }),
+        ];
+        for (from, transfer) in cases {
+            assert!(from.after(transfer).is_err(), "{:?} {} was allowed", from, transfer);
+        }
+    }
+
+    #[test]
+    fn of_rejects_unknown_kinds_and_negative_counts() {
+        let mut bad = user(OwnershipType::RustOwned, 1);
+        bad.ownership = 99;
+        assert!(matches!(Ownership::of(&bad), Err(DatabaseError::OwnershipViolation(_))));
+        let negative = user(OwnershipType::COwned, -1);
+        assert!(matches!(Ownership::of(&negative), Err(DatabaseError::OwnershipViolation(_))));
+    }
+
+    #[test]
+    fn hand_off_runs_the_call_and_checks_the_result() {
+        let mut target = user(OwnershipType::RustOwned, 1);
+        let ptr: *mut UserStruct = &mut target;
+        let result = unsafe {
+            hand_off(ptr, &[Transfer::Share { to: Side::C }], || {
+                (*ptr).ownership = OwnershipType::SharedRustPrimary as i32;
+                (*ptr).ref_count += 1;
+            })
+        };
+        assert_eq!(result, Ok(()));
+        assert_eq!(Ownership::of(&target), Ok(state(OwnershipType::SharedRustPrimary, 2)));
+    }
+
+    #[test]
+    fn hand_off_reports_a_call_that_left_the_wrong_state() {
+        let mut target = user(OwnershipType::RustOwned, 1);
+        let ptr: *mut UserStruct = &mut target;
+        // the count went up but the kind was never switched to shared
+        let result = unsafe { hand_off(ptr, &[Transfer::Share { to: Side::C }], || (*ptr).ref_count += 1) };
+        assert!(matches!(result, Err(DatabaseError::OwnershipViolation(_))));
+    }
+
+    #[test]
+    fn refused_hand_off_makes_no_call() {
+        let mut called = false;
+        let mut target = user(OwnershipType::SharedCPrimary, 2);
+        let result = unsafe { hand_off(&mut target, &[Transfer::Free { by: Side::C }], || called = true) };
+        assert!(matches!(result, Err(DatabaseError::OwnershipViolation(_))));
+        assert!(!called);
+
+        // a Free partway through refuses everything after it, before anything runs
+        let mut target = user(OwnershipType::RustOwned, 1);
+        let transfers = [Transfer::Free { by: Side::Rust }, Transfer::Share { to: Side::C }];
+        let result = unsafe { hand_off(&mut target, &transfers, || called = true) };
+        assert!(matches!(result, Err(DatabaseError::OwnershipViolation(_))));
+        assert!(!called);
+        assert_eq!(Ownership::of(&target), Ok(state(OwnershipType::RustOwned, 1)));
+    }
+}
@@ -0,0 +1,117 @@
+// Signup password rules, checked before a user reaches either backend.
+use crate::database_fix_full::DatabaseError;
+
+pub const MIN_PASSWORD_LEN: usize = 1;
+// C keeps 99 bytes and Rust 999, a password either can hold in full works wherever the user lands
+pub const MAX_PASSWORD_LEN: usize = 99;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct PasswordPolicy {
+    // lengths in bytes
+    pub min_len: usize,
+    pub max_len: usize,
+    pub require_lowercase: bool,
+    pub require_uppercase: bool,
+    pub require_digit: bool,
+    // anything that isn't an ASCII letter or digit
+    pub require_symbol: bool,
+}
+
+impl Default for PasswordPolicy {
+    fn default() -> Self {
+        PasswordPolicy {
+            min_len: MIN_PASSWORD_LEN,
+            max_len: MAX_PASSWORD_LEN,
+            require_lowercase: false,
+            require_uppercase: false,
+            require_digit: false,
+            require_symbol: false,
+        }
+    }
+}
+
+impl PasswordPolicy {
+    /// Err(InvalidInput) naming the first rule `password` breaks
+    pub fn check(&self, password: &str) -> Result<(), DatabaseError> {
+        let reject = |why: String| Err(DatabaseError::InvalidInput(format!("password {}", why)));
+        if password.len() < self.min_len {
+            return reject(format!("must be at least {} bytes", self.min_len));
+        }
+        if password.len() > self.max_len {
+            return reject(format!("must be at most {} bytes", self.max_len));
+        }
+        if password.contains('\0') {
+            return reject("must not contain a NUL byte".to_string());
+        }
+        for (required, class, present) in [
+            (self.require_lowercase, "a lowercase letter", password.chars().any(|c| c.is_ascii_lowercase())),
+            (self.require_uppercase, "an uppercase letter", password.chars().any(|c| c.is_ascii_uppercase())),
+            (self.require_digit, "a digit", password.chars().any(|c| c.is_ascii_digit())),
+            (self.require_symbol, "a symbol", password.chars().any(|c| !c.is_ascii_alphanumeric())),
+        ] {
+            if required && !present {
+                return reject(format!("must contain {}", class));
+            }
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn rejected(policy: &PasswordPolicy, password: &str) -> bool {
+        matches!(policy.check(password), Err(DatabaseError::InvalidInput(_)))
+    }
+
+    #[test]
+    fn default_length_bounds() {
+        let policy = PasswordPolicy::default();
+        assert!(rejected(&policy, ""));
+        assert_eq!(policy.check("x"), Ok(()));
+        assert_eq!(policy.check(&"x".repeat(99)), Ok(()));
+        assert!(rejected(&policy, &"x".repeat(100)));
+    }
+
+    #[test]
+    fn custom_length_bounds() {
+        let policy = PasswordPolicy { min_len: 8, max_len: 12, ..PasswordPolicy::default() };
+        assert!(rejected(&policy, "1234567"));
+        assert_eq!(policy.check("12345678"), Ok(()));
+        assert_eq!(policy.check("123456789012"), Ok(()));
+        assert!(rejected(&policy, "1234567890123"));
+        // bytes, not characters
+        assert!(rejected(&policy, &"é".repeat(7)));
+    }
+
+    #[test]
+    fn nul_is_rejected() {
+        assert!(rejected(&PasswordPolicy::default(), "pass\0word"));
+    }
+
+    #[test]
+    fn each_character_class_is_required_when_asked() {
+        let all = PasswordPolicy {
+            require_lowercase: true,
+            require_uppercase: true,
+            require_digit: true,
+            require_symbol: true,
+            ..PasswordPolicy::default()
+        };
+        assert_eq!(all.check("aB3$"), Ok(()));
+        for (missing, password) in [
+            ("a lowercase letter", "AB3$"),
+            ("an uppercase letter", "ab3$"),
+            ("a digit", "aBc$"),
+            ("a symbol", "aB34"),
+        ] {
+            assert_eq!(
+                all.check(password),
+                Err(DatabaseError::InvalidInput(format!("password must contain {}", missing)))
+            );
+        }
+        // off by default
+        assert_eq!(PasswordPolicy::default().check("aaaa"), Ok(()));
+    }
+}
@@ -0,0 +1,85 @@
+// Whether the two backends agree. Every user C is responsible for should have a Rust record,
+// the copy a join made, holding the same values, and every Rust user should be listed in C,
+// either shared or as the C user it was copied from. Shared Rust users are one record both
+// backends point to, so they can only go missing, never differ.
+use std::fmt;
+
+use crate::database_fix_full::{byte_to_string, UserStruct};
+use crate::wal::Backend;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Field {
+    UserId,
+    Email,
+    Inactivity,
+    Active,
+    SessionToken,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum Discrepancy {
+    // the other backend holds the user, `from` doesn't
+    Missing { username: String, from: Backend },
+    Differs { username: String, field: Field, rust: String, c: String },
+}
+
+impl Discrepancy {
+    pub fn username(&self) -> &str {
+        match self {
+            Discrepancy::Missing { username, .. } | Discrepancy::Differs { username, .. } => username,
+        }
+    }
+}
+
+impl fmt::Display for Discrepancy {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Discrepancy::Missing { username, from } => write!(f, "{}: missing from {:?}", username, from),
+            Discrepancy::Differs { username, field, rust, c } => {
+                write!(f, "{}: {:?} differs (Rust {}, C {})", username, field, rust, c)
+            }
+        }
+    }
+}
+
+/// Every field the Rust record `rust` and the C record `c` of one user disagree on
+pub fn compare(rust: &UserStruct, c: &UserStruct) -> Vec<Discrepancy> {
+    let username = byte_to_string(&rust.username);
+    [
+        (Field::UserId, rust.user_id.to_string(), c.user_id.to_string()),
+        (Field::Email, byte_to_string(&rust.email), byte_to_string(&c.email)),
+        (Field::Inactivity, rust.inactivity_count.to_string(), c.inactivity_count.to_string()),
+        (Field::Active, rust.is_active.to_string(), c.is_active.to_string()),
+        (Field::SessionToken, byte_to_string(&rust.session_token), byte_to_string(&c.session_token)),
+    ]
+    .into_iter()
+    .filter(|(_, rust, c)| rust != c)
+    .map(|(field, rust, c)| Discrepancy::Differs { username: username.clone(), field, rust, c })
+    .collect()
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Finding {
+    pub discrepancy: Discrepancy,
+    // gone once the repair ran, always false for a check without one
+    pub repaired: bool,
+}
+
+#[derive(Debug, Clone, Default)]
+pub struct ReconcileReport {
+    pub day: i32,
+    // the backend whose values were copied over the other's, None for a check only
+    pub source_of_truth: Option<Backend>,
+    // what the check found before any repair, C users first
+    pub findings: Vec<Finding>,
+}
+
+impl ReconcileReport {
+    pub fn is_consistent(&self) -> bool {
+        self.findings.iter().all(|finding| finding.repaired)
+    }
+
+    pub fn repaired(&self) -> usize {
+        self.findings.iter().filter(|finding| finding.repaired).count()
+    }
+}
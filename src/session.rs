@@ -0,0 +1,657 @@
+// Session table for both backends. It used to live in C, which never reused a slot and
+// called exit(1) once MAX_SESSIONS sessions had ever been created.
+use std::collections::HashMap;
+
+use crate::database_fix_full::DatabaseError;
+use crate::signed_token::{TokenClaims, TokenSigner, SIGNING_KEY_LEN};
+use crate::wal::Backend;
+
+pub const MAX_SESSIONS: usize = 100;
+// defaults for SessionLifetimes, a one day absolute lifetime is the old end-of-day logout
+pub const SESSION_MAX_IDLE_TIME: i32 = 1;
+pub const SESSION_MAX_AGE_DAYS: i32 = 1;
+pub const REFRESH_TOKEN_MAX_AGE_DAYS: i32 = 7;
+// tokens are 120 random bits in hex, nothing about the user or the clock
+const SESSION_TOKEN_RANDOM_BYTES: usize = 15;
+const SESSION_TOKEN_MAX_ATTEMPTS: usize = 8;
+// label for logins that don't name their device or client
+pub const DEFAULT_CLIENT: &str = "default";
+// how much of a token listings show, enough to tell sessions apart but not to use one
+pub const TOKEN_PREFIX_LEN: usize = 8;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum EvictionPolicy {
+    // a full table only makes room by dropping expired sessions, otherwise the login fails
+    ExpiredOnly,
+    // expired sessions go first, then the least recently used live one
+    LeastRecentlyUsed,
+}
+
+#[derive(Clone, Copy, PartialEq, Eq)]
+pub enum TokenMode {
+    // random tokens, only good while the table holds their session
+    Opaque,
+    // tokens carry their user and expiry signed with the key and are checked without the
+    // table, sessions ended early go on a revocation list
+    Signed { key: [u8; SIGNING_KEY_LEN] },
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct SessionLifetimes {
+    // a live session expires on the first tick that finds it idle for longer than this
+    pub max_idle: i32,
+    // days from login until a session expires however active it is
+    pub max_age_days: i32,
+    // days a refresh token can still be exchanged for a new session
+    pub refresh_max_age_days: i32,
+}
+
+impl Default for SessionLifetimes {
+    fn default() -> Self {
+        SessionLifetimes {
+            max_idle: SESSION_MAX_IDLE_TIME,
+            max_age_days: SESSION_MAX_AGE_DAYS,
+            refresh_max_age_days: REFRESH_TOKEN_MAX_AGE_DAYS,
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum SessionState {
+    Active,
+    // went past its idle or absolute lifetime
+    Expired,
+    // logged out or revoked
+    Revoked,
+}
+
+/// What a token is worth right now
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum SessionStatus {
+    // user ids are only unique within a backend
+    Valid { user_id: i32, backend: Backend, idle: i32 },
+    Expired,
+    Revoked,
+    // never issued, or already dropped from the table
+    Unknown,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Session {
+    pub user_id: i32,
+    // the backend responsible for the user when the session started
+    pub backend: Backend,
+    pub username: String,
+    pub token: String,
+    // device or client label, a user has at most one live session per client
+    pub client: String,
+    pub idle_time: i32,
+    pub state: SessionState,
+    pub created_day: i32,
+    // the manager's use counter when the session was created or last touched, for LRU eviction
+    last_used: u64,
+}
+
+impl Session {
+    pub fn new(user_id: i32, backend: Backend, username: &str, token: &str, client: &str, created_day: i32) -> Self {
+        Session {
+            user_id,
+            backend,
+            username: username.to_string(),
+            token: token.to_string(),
+            client: client.to_string(),
+            idle_time: 0,
+            state: SessionState::Active,
+            created_day,
+            last_used: 0,
+        }
+    }
+
+    pub fn is_active(&self) -> bool {
+        self.state == SessionState::Active
+    }
+
+    /// Higher for a session used more recently, only comparable within one manager
+    pub fn last_used(&self) -> u64 {
+        self.last_used
+    }
+
+    pub fn status(&self) -> SessionStatus {
+        match self.state {
+            SessionState::Active => {
+                SessionStatus::Valid { user_id: self.user_id, backend: self.backend, idle: self.idle_time }
+            }
+            SessionState::Expired => SessionStatus::Expired,
+            SessionState::Revoked => SessionStatus::Revoked,
+        }
+    }
+}
+
+/// One row of the session inventory, safe to print
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct SessionInfo {
+    pub username: String,
+    pub user_id: i32,
+    // which backend owns the user
+    pub backend: Backend,
+    pub client: String,
+    pub token_prefix: String,
+    pub created_day: i32,
+    pub idle_time: i32,
+    pub state: SessionState,
+}
+
+impl SessionInfo {
+    pub fn new(session: &Session) -> Self {
+        SessionInfo {
+            username: session.username.clone(),
+            user_id: session.user_id,
+            backend: session.backend,
+            client: session.client.clone(),
+            token_prefix: session.token.chars().take(TOKEN_PREFIX_LEN).collect(),
+            created_day: session.created_day,
+            idle_time: session.idle_time,
+            state: session.state,
+        }
+    }
+}
+
+/// Lets a client start a new session for the same user and client without the password.
+/// Each one is good for a single exchange, which hands out its replacement.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct RefreshToken {
+    pub token: String,
+    pub username: String,
+    pub client: String,
+    pub issued_day: i32,
+}
+
+pub struct SessionManager {
+    // a removed session leaves a None that the next one reuses
+    slots: Vec<Option<Session>>,
+    free_slots: Vec<usize>,
+    // token -> slot
+    index: HashMap<String, usize>,
+    capacity: usize,
+    policy: EvictionPolicy,
+    lifetimes: SessionLifetimes,
+    uses: u64,
+    // refresh token -> grant, these outlive the sessions they were issued for
+    refresh: HashMap<String, RefreshToken>,
+    // None in TokenMode::Opaque
+    signer: Option<TokenSigner>,
+    // token -> (its expiry day, why it ended) for sessions that ended early, kept until the
+    // token would have expired anyway. Only signed validation reads it.
+    revoked: HashMap<String, (i32, SessionState)>,
+}
+
+impl SessionManager {
+    pub fn new(capacity: usize) -> Self {
+        SessionManager {
+            slots: Vec::new(),
+            free_slots: Vec::new(),
+            index: HashMap::new(),
+            capacity,
+            policy: EvictionPolicy::LeastRecentlyUsed,
+            lifetimes: SessionLifetimes::default(),
+            uses: 0,
+            refresh: HashMap::new(),
+            signer: None,
+            revoked: HashMap::new(),
+        }
+    }
+
+    /// Applies to sessions created from now on, existing tokens only validate in the mode
+    /// that minted them
+    pub fn set_token_mode(&mut self, mode: TokenMode) {
+        self.signer = match mode {
+            TokenMode::Opaque => None,
+            TokenMode::Signed { key } => Some(TokenSigner::new(key)),
+        };
+    }
+
+    pub fn set_policy(&mut self, policy: EvictionPolicy) {
+        self.policy = policy;
+    }
+
+    pub fn set_lifetimes(&mut self, lifetimes: SessionLifetimes) {
+        self.lifetimes = lifetimes;
+    }
+
+    pub fn iter(&self) -> impl Iterator<Item = &Session> {
+        self.slots.iter().filter_map(|slot| slot.as_ref())
+    }
+
+    pub fn contains(&self, token: &str) -> bool {
+        self.index.contains_key(token)
+    }
+
+    pub fn get(&self, token: &str) -> Option<&Session> {
+        self.index.get(token).and_then(|&slot| self.slots[slot].as_ref())
+    }
+
+    /// Live sessions of `username`, oldest first
+    pub fn live_for(&self, username: &str) -> Vec<&Session> {
+        let mut sessions: Vec<&Session> = self
+            .iter()
+            .filter(|session| session.is_active() && session.username == username)
+            .collect();
+        sessions.sort_by_key(|session| session.last_used);
+        sessions
+    }
+
+    /// A token for a session the user would start on `day`, in the current token mode. Nothing
+    /// is stored, `login` installs the session once it is logged.
+    pub fn mint(&self, user_id: i32, backend: Backend, day: i32) -> Result<String, DatabaseError> {
+        match &self.signer {
+            Some(signer) => self.generate_signed_token(signer, user_id, backend, day),
+            None => self.generate_token(),
+        }
+    }
+
+    /// Start a session with a token from `mint`, ending the user's previous session on the
+    /// same client and making room first if the table is full
+    pub fn login(&mut self, session: Session) -> Result<(), DatabaseError> {
+        if session.username.is_empty() {
+            return Err(DatabaseError::InvalidInput("session for an empty username".to_string()));
+        }
+        self.revoke_where(|live| live.username == session.username && live.client == session.client);
+        self.restore(session)
+    }
+
+    /// Put back a session saved from `iter`, replacing any with the same token
+    pub fn restore(&mut self, session: Session) -> Result<(), DatabaseError> {
+        self.remove(&session.token);
+        self.insert(session)
+    }
+
+    pub fn remove(&mut self, token: &str) -> Option<Session> {
+        let slot = self.index.remove(token)?;
+        self.free_slots.push(slot);
+        self.slots[slot].take()
+    }
+
+    /// Move every session and refresh token of `old` over to `new`, live or not
+    pub fn rename(&mut self, old: &str, new: &str) {
+        for session in self.slots.iter_mut().flatten().filter(|session| session.username == old) {
+            session.username = new.to_string();
+        }
+        for grant in self.refresh.values_mut().filter(|grant| grant.username == old) {
+            grant.username = new.to_string();
+        }
+    }
+
+    /// End every live session matching `ends`, they stay in the table as inactive until the
+    /// end of the day or eviction. Returns the sessions ended.
+    pub fn revoke_where(&mut self, ends: impl Fn(&Session) -> bool) -> Vec<Session> {
+        let revoked: Vec<Session> = self
+            .slots
+            .iter_mut()
+            .flatten()
+            .filter(|session| session.is_active() && ends(session))
+            .map(|session| {
+                session.state = SessionState::Revoked;
+                session.clone()
+            })
+            .collect();
+        for session in &revoked {
+            self.deny(session, SessionState::Revoked);
+        }
+        revoked
+    }
+
+    /// Read-only check of `token` on `today`, any live session resolves to its user's id.
+    /// Signed tokens are checked against their signature and the revocation list alone, they
+    /// don't carry idle time so it reads 0.
+    pub fn status(&self, token: &str, today: i32) -> SessionStatus {
+        if let Some(signer) = &self.signer {
+            let Some(claims) = signer.verify(token) else {
+                return SessionStatus::Unknown;
+            };
+            return match self.revoked.get(token) {
+                Some((_, SessionState::Expired)) => SessionStatus::Expired,
+                Some(_) => SessionStatus::Revoked,
+                None if today >= claims.expires_day => SessionStatus::Expired,
+                None => SessionStatus::Valid { user_id: claims.user_id, backend: claims.backend, idle: 0 },
+            };
+        }
+        match self.get(token) {
+            None => SessionStatus::Unknown,
+            Some(session) if session.is_active() && self.too_old(session, today) => SessionStatus::Expired,
+            Some(session) => session.status(),
+        }
+    }
+
+    /// The daily idle tick. A live session past its idle or absolute lifetime expires, any
+    /// other gets one more idle day. Returns the status after the tick.
+    pub fn tick(&mut self, token: &str, today: i32) -> SessionStatus {
+        let Some(&slot) = self.index.get(token) else {
+            return SessionStatus::Unknown;
+        };
+        let max_idle = self.lifetimes.max_idle;
+        let too_old = self.slots[slot].as_ref().is_some_and(|session| self.too_old(session, today));
+        let session = self.slots[slot].as_mut().expect("index points at a live slot");
+        if session.is_active() {
+            if session.idle_time > max_idle || too_old {
+                session.state = SessionState::Expired;
+                let expired = session.clone();
+                self.deny(&expired, SessionState::Expired);
+            } else {
+                session.idle_time += 1;
+            }
+        }
+        self.slots[slot].as_ref().expect("index points at a live slot").status()
+    }
+
+    /// `tick` for every live session
+    pub fn tick_all(&mut self, today: i32) {
+        let live: Vec<String> = self
+            .iter()
+            .filter(|session| session.is_active())
+            .map(|session| session.token.clone())
+            .collect();
+        for token in live {
+            self.tick(&token, today);
+        }
+    }
+
+    /// Sliding renewal, activity on a live session resets its idle time. The absolute
+    /// lifetime still counts from login.
+    pub fn touch(&mut self, token: &str, today: i32) -> SessionStatus {
+        let status = self.status(token, today);
+        let slot = match (status, self.index.get(token)) {
+            (SessionStatus::Valid { .. }, Some(&slot)) => slot,
+            _ => return status,
+        };
+        self.uses += 1;
+        let session = self.slots[slot].as_mut().expect("index points at a live slot");
+        session.idle_time = 0;
+        session.last_used = self.uses;
+        status
+    }
+
+    /// End of day sweep: expire sessions past their absolute lifetime, then drop every session
+    /// that is no longer live and return them so their users can be marked inactive. Refresh
+    /// tokens past their own lifetime go too.
+    pub fn end_day(&mut self, today: i32) -> Vec<Session> {
+        let ended: Vec<String> = self
+            .iter()
+            .filter(|session| !session.is_active() || self.too_old(session, today))
+            .map(|session| session.token.clone())
+            .collect();
+        let refresh_max_age = self.lifetimes.refresh_max_age_days;
+        self.refresh.retain(|_, grant| today - grant.issued_day < refresh_max_age);
+        self.revoked.retain(|_, (expires_day, _)| today < *expires_day);
+        let mut removed = Vec::new();
+        for token in ended {
+            let Some(mut session) = self.remove(&token) else {
+                continue;
+            };
+            if session.is_active() {
+                session.state = SessionState::Expired;
+                // lifetimes can shrink after a token is signed
+                self.deny(&session, SessionState::Expired);
+            }
+            removed.push(session);
+        }
+        removed
+    }
+
+    /// Signed tokens ended before their expiry, as (token, expiry day, why)
+    pub fn revoked_tokens(&self) -> impl Iterator<Item = (&String, i32, SessionState)> {
+        self.revoked.iter().map(|(token, &(expires_day, state))| (token, expires_day, state))
+    }
+
+    pub fn restore_revoked(&mut self, token: &str, expires_day: i32, state: SessionState) {
+        self.revoked.insert(token.to_string(), (expires_day, state));
+    }
+
+    pub fn refresh_tokens(&self) -> impl Iterator<Item = &RefreshToken> {
+        self.refresh.values()
+    }
+
+    /// Hand out `grant`, replacing the user's earlier refresh token for the same client
+    pub fn issue_refresh(&mut self, grant: RefreshToken) {
+        self.refresh
+            .retain(|_, other| other.username != grant.username || other.client != grant.client);
+        self.refresh.insert(grant.token.clone(), grant);
+    }
+
+    /// The grant behind `token` if it can still be exchanged on `today`
+    pub fn refresh_grant(&self, token: &str, today: i32) -> Result<&RefreshToken, DatabaseError> {
+        let grant = self
+            .refresh
+            .get(token)
+            .ok_or_else(|| DatabaseError::NotFound("Refresh token".to_string()))?;
+        if today - grant.issued_day >= self.lifetimes.refresh_max_age_days {
+            return Err(DatabaseError::SessionExpired);
+        }
+        Ok(grant)
+    }
+
+    /// Use up a refresh token, it can't be exchanged again
+    pub fn take_refresh(&mut self, token: &str) -> Option<RefreshToken> {
+        self.refresh.remove(token)
+    }
+
+    /// Drop every refresh token matching `ends`, returning how many there were
+    pub fn revoke_refresh_where(&mut self, ends: impl Fn(&RefreshToken) -> bool) -> usize {
+        let before = self.refresh.len();
+        self.refresh.retain(|_, grant| !ends(grant));
+        before - self.refresh.len()
+    }
+
+    fn too_old(&self, session: &Session, today: i32) -> bool {
+        today - session.created_day >= self.lifetimes.max_age_days
+    }
+
+    fn insert(&mut self, mut session: Session) -> Result<(), DatabaseError> {
+        if self.index.len() >= self.capacity {
+            self.evict()?;
+        }
+        self.uses += 1;
+        session.last_used = self.uses;
+        let token = session.token.clone();
+        let slot = match self.free_slots.pop() {
+            Some(slot) => {
+                self.slots[slot] = Some(session);
+                slot
+            }
+            None => {
+                self.slots.push(Some(session));
+                self.slots.len() - 1
+            }
+        };
+        self.index.insert(token, slot);
+        Ok(())
+    }
+
+    fn evict(&mut self) -> Result<(), DatabaseError> {
+        let oldest = |expired_only: bool| {
+            self.iter()
+                .filter(|session| !expired_only || !session.is_active())
+                .min_by_key(|session| session.last_used)
+                .map(|session| session.token.clone())
+        };
+        let victim = match self.policy {
+            EvictionPolicy::ExpiredOnly => oldest(true),
+            EvictionPolicy::LeastRecentlyUsed => oldest(true).or_else(|| oldest(false)),
+        };
+        match victim {
+            Some(token) => {
+                if let Some(session) = self.remove(&token).filter(Session::is_active) {
+                    self.deny(&session, SessionState::Revoked);
+                }
+                Ok(())
+            }
+            None => Err(DatabaseError::Full),
+        }
+    }
+
+    // a signed token outlives its slot, so one that ends early has to be remembered. Kept in
+    // either mode, log replay runs before the mode is set.
+    fn deny(&mut self, session: &Session, state: SessionState) {
+        let expires_day = match self.signer.as_ref().and_then(|signer| signer.verify(&session.token)) {
+            Some(claims) => claims.expires_day,
+            None => session.created_day + self.lifetimes.max_age_days,
+        };
+        self.revoked.insert(session.token.clone(), (expires_day, state));
+    }
+
+    fn generate_signed_token(
+        &self,
+        signer: &TokenSigner,
+        user_id: i32,
+        backend: Backend,
+        day: i32,
+    ) -> Result<String, DatabaseError> {
+        let claims = TokenClaims {
+            user_id,
+            backend,
+            issued_day: day,
+            expires_day: day + self.lifetimes.max_age_days,
+        };
+        for _ in 0..SESSION_TOKEN_MAX_ATTEMPTS {
+            let token = signer.sign(&claims)?;
+            if !self.contains(&token) && !self.revoked.contains_key(&token) {
+                return Ok(token);
+            }
+        }
+        Err(DatabaseError::BackendFailure("could not generate an unused session token".to_string()))
+    }
+
+    /// A fresh random token, unused by any session or refresh token
+    pub fn generate_token(&self) -> Result<String, DatabaseError> {
+        let mut bytes = [0u8; SESSION_TOKEN_RANDOM_BYTES];
+        for _ in 0..SESSION_TOKEN_MAX_ATTEMPTS {
+            getrandom::getrandom(&mut bytes)
+                .map_err(|e| DatabaseError::BackendFailure(format!("no randomness for a session token: {}", e)))?;
+            let token: String = bytes.iter().map(|b| format!("{:02x}", b)).collect();
+            if !self.contains(&token) && !self.refresh.contains_key(&token) {
+                return Ok(token);
+            }
+        }
+        Err(DatabaseError::BackendFailure("could not generate an unused session token".to_string()))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const LONG: i32 = 100;
+
+    fn manager(capacity: usize, max_idle: i32, max_age_days: i32) -> SessionManager {
+        let mut sessions = SessionManager::new(capacity);
+        sessions.set_lifetimes(SessionLifetimes { max_idle, max_age_days, ..SessionLifetimes::default() });
+        sessions
+    }
+
+    fn create(
+        sessions: &mut SessionManager,
+        user_id: i32,
+        username: &str,
+        client: &str,
+        day: i32,
+    ) -> Result<String, DatabaseError> {
+        let token = sessions.mint(user_id, Backend::Rust, day)?;
+        sessions.login(Session::new(user_id, Backend::Rust, username, &token, client, day))?;
+        Ok(token)
+    }
+
+    fn valid(sessions: &SessionManager, token: &str, today: i32) -> bool {
+        matches!(sessions.status(token, today), SessionStatus::Valid { .. })
+    }
+
+    #[test]
+    fn full_table_evicts_the_least_recently_used() {
+        let mut sessions = manager(2, LONG, LONG);
+        let alice = create(&mut sessions, 1, "alice", DEFAULT_CLIENT, 0).unwrap();
+        let bob = create(&mut sessions, 2, "bob", DEFAULT_CLIENT, 0).unwrap();
+        sessions.touch(&alice, 0);
+        let carol = create(&mut sessions, 3, "carol", DEFAULT_CLIENT, 0).unwrap();
+        assert_eq!(sessions.status(&bob, 0), SessionStatus::Unknown);
+        assert!(valid(&sessions, &alice, 0) && valid(&sessions, &carol, 0));
+        assert_eq!(sessions.iter().count(), 2);
+    }
+
+    #[test]
+    fn full_table_only_evicts_ended_sessions_when_asked() {
+        let mut sessions = manager(2, LONG, LONG);
+        sessions.set_policy(EvictionPolicy::ExpiredOnly);
+        let alice = create(&mut sessions, 1, "alice", DEFAULT_CLIENT, 0).unwrap();
+        let bob = create(&mut sessions, 2, "bob", DEFAULT_CLIENT, 0).unwrap();
+        assert_eq!(create(&mut sessions, 3, "carol", DEFAULT_CLIENT, 0), Err(DatabaseError::Full));
+
+        sessions.revoke_where(|session| session.username == "alice");
+        let carol = create(&mut sessions, 3, "carol", DEFAULT_CLIENT, 0).unwrap();
+        assert_eq!(sessions.status(&alice, 0), SessionStatus::Unknown);
+        assert!(valid(&sessions, &bob, 0) && valid(&sessions, &carol, 0));
+    }
+
+    #[test]
+    fn idle_session_expires_once_past_max_idle() {
+        let mut sessions = manager(4, 2, LONG);
+        let token = create(&mut sessions, 1, "alice", DEFAULT_CLIENT, 0).unwrap();
+        for idle in 1..=3 {
+            assert_eq!(sessions.tick(&token, 0), SessionStatus::Valid { user_id: 1, backend: Backend::Rust, idle });
+        }
+        assert_eq!(sessions.tick(&token, 0), SessionStatus::Expired);
+        assert_eq!(sessions.touch(&token, 0), SessionStatus::Expired);
+        assert_eq!(sessions.end_day(0).len(), 1);
+        assert_eq!(sessions.status(&token, 0), SessionStatus::Unknown);
+    }
+
+    #[test]
+    fn activity_resets_the_idle_time() {
+        let mut sessions = manager(4, 1, LONG);
+        let token = create(&mut sessions, 1, "alice", DEFAULT_CLIENT, 0).unwrap();
+        for _ in 0..5 {
+            sessions.tick(&token, 0);
+            sessions.touch(&token, 0);
+        }
+        assert_eq!(sessions.status(&token, 0), SessionStatus::Valid { user_id: 1, backend: Backend::Rust, idle: 0 });
+    }
+
+    #[test]
+    fn session_expires_at_max_age_however_active() {
+        let mut sessions = manager(4, LONG, 2);
+        let token = create(&mut sessions, 1, "alice", DEFAULT_CLIENT, 3).unwrap();
+        assert_eq!(sessions.touch(&token, 4), SessionStatus::Valid { user_id: 1, backend: Backend::Rust, idle: 0 });
+        assert!(sessions.end_day(4).is_empty());
+        assert_eq!(sessions.status(&token, 5), SessionStatus::Expired);
+        assert_eq!(sessions.touch(&token, 5), SessionStatus::Expired);
+        let ended = sessions.end_day(5);
+        assert_eq!(ended.len(), 1);
+        assert_eq!(ended[0].state, SessionState::Expired);
+        assert_eq!(sessions.status(&token, 5), SessionStatus::Unknown);
+    }
+
+    #[test]
+    fn revoked_token_is_denied() {
+        let mut sessions = manager(4, LONG, LONG);
+        let token = create(&mut sessions, 1, "alice", DEFAULT_CLIENT, 0).unwrap();
+        let phone = create(&mut sessions, 1, "alice", "phone", 0).unwrap();
+        assert_eq!(sessions.revoke_where(|session| session.token == token).len(), 1);
+        assert_eq!(sessions.status(&token, 0), SessionStatus::Revoked);
+        // activity doesn't bring it back, the other client is untouched
+        assert_eq!(sessions.touch(&token, 0), SessionStatus::Revoked);
+        assert!(valid(&sessions, &phone, 0));
+        // a new login on the same client revokes the one before
+        let again = create(&mut sessions, 1, "alice", "phone", 0).unwrap();
+        assert_eq!(sessions.status(&phone, 0), SessionStatus::Revoked);
+        assert!(valid(&sessions, &again, 0));
+    }
+
+    #[test]
+    fn revoked_signed_token_stays_denied_after_its_slot_is_gone() {
+        let mut sessions = manager(4, LONG, LONG);
+        sessions.set_token_mode(TokenMode::Signed { key: [7; SIGNING_KEY_LEN] });
+        let token = create(&mut sessions, 1, "alice", DEFAULT_CLIENT, 0).unwrap();
+        assert!(valid(&sessions, &token, 0));
+        sessions.revoke_where(|session| session.username == "alice");
+        assert_eq!(sessions.end_day(0).len(), 1);
+        assert!(!sessions.contains(&token));
+        assert_eq!(sessions.status(&token, 1), SessionStatus::Revoked);
+    }
+}
@@ -0,0 +1,97 @@
+// Stateless session tokens. The claims travel in the token signed with a server key, so
+// checking one needs no table lookup.
+//
+// token: base64url without padding of
+//        version u8 | backend u8 (0 Rust, 1 C) | user id i32 | issue day u16 |
+//        expiry day u16 | nonce (3 bytes) | first 10 bytes of HMAC-SHA256 over everything before it
+// 31 characters, so it still fits a user's 32 byte session_token with its NUL.
+// C and Rust hand out overlapping ids, the backend says whose id it is.
+use base64::engine::general_purpose::URL_SAFE_NO_PAD;
+use base64::Engine;
+use hmac::{Hmac, Mac};
+use sha2::Sha256;
+
+use crate::database_fix_full::DatabaseError;
+use crate::wal::Backend;
+
+pub const SIGNING_KEY_LEN: usize = 32;
+const TOKEN_VERSION: u8 = 1;
+const NONCE_LEN: usize = 3;
+const CLAIMS_LEN: usize = 1 + 1 + 4 + 2 + 2 + NONCE_LEN;
+// 80 bits, the shortest truncation RFC 2104 recommends
+const MAC_LEN: usize = 10;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct TokenClaims {
+    pub user_id: i32,
+    // the backend responsible for the user
+    pub backend: Backend,
+    pub issued_day: i32,
+    // first day the token is no longer good
+    pub expires_day: i32,
+}
+
+#[derive(Clone)]
+pub struct TokenSigner {
+    key: [u8; SIGNING_KEY_LEN],
+}
+
+impl TokenSigner {
+    pub fn new(key: [u8; SIGNING_KEY_LEN]) -> Self {
+        TokenSigner { key }
+    }
+
+    /// A new token for `claims`, the random nonce keeps two logins on the same day apart
+    pub fn sign(&self, claims: &TokenClaims) -> Result<String, DatabaseError> {
+        let day = |day: i32| {
+            u16::try_from(day)
+                .map_err(|_| DatabaseError::InvalidInput(format!("day {} does not fit a signed token", day)))
+        };
+        let mut nonce = [0u8; NONCE_LEN];
+        getrandom::getrandom(&mut nonce)
+            .map_err(|e| DatabaseError::BackendFailure(format!("no randomness for a session token: {}", e)))?;
+
+        let mut bytes = Vec::with_capacity(CLAIMS_LEN + MAC_LEN);
+        bytes.push(TOKEN_VERSION);
+        bytes.push(match claims.backend {
+            Backend::Rust => 0,
+            Backend::C => 1,
+        });
+        bytes.extend_from_slice(&claims.user_id.to_le_bytes());
+        bytes.extend_from_slice(&day(claims.issued_day)?.to_le_bytes());
+        bytes.extend_from_slice(&day(claims.expires_day)?.to_le_bytes());
+        bytes.extend_from_slice(&nonce);
+        let tag = self.mac(&bytes).finalize().into_bytes();
+        bytes.extend_from_slice(&tag[..MAC_LEN]);
+        Ok(URL_SAFE_NO_PAD.encode(bytes))
+    }
+
+    /// The claims of a token this key signed, None for anything else
+    pub fn verify(&self, token: &str) -> Option<TokenClaims> {
+        let bytes = URL_SAFE_NO_PAD.decode(token).ok()?;
+        if bytes.len() != CLAIMS_LEN + MAC_LEN || bytes[0] != TOKEN_VERSION {
+            return None;
+        }
+        let (claims, tag) = bytes.split_at(CLAIMS_LEN);
+        // constant time, a mismatch says nothing about how much of the tag was right
+        self.mac(claims).verify_truncated_left(tag).ok()?;
+        let backend = match claims[1] {
+            0 => Backend::Rust,
+            1 => Backend::C,
+            _ => return None,
+        };
+        let day = |at: usize| u16::from_le_bytes([claims[at], claims[at + 1]]) as i32;
+        Some(TokenClaims {
+            user_id: i32::from_le_bytes([claims[2], claims[3], claims[4], claims[5]]),
+            backend,
+            issued_day: day(6),
+            expires_day: day(8),
+        })
+    }
+
+    fn mac(&self, message: &[u8]) -> Hmac<Sha256> {
+        let mut mac = Hmac::<Sha256>::new_from_slice(&self.key).expect("HMAC takes a key of any length");
+        mac.update(message);
+        mac
+    }
+}
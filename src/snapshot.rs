@@ -0,0 +1,548 @@
+// Versioned binary snapshots of both backends.
+//
+// header:  magic (8 bytes) | schema version u32 | payload length u64 | SHA-256 of payload
+// payload: day i32 | last WAL lsn u64 | rust users | c users | sessions | refresh tokens |
+//          revoked signed tokens | login failures
+// session: user id | backend | username | token | idle time | state | created day | client
+// state is 0 expired, 1 active, 2 revoked
+// refresh token: token | username | client | issued day
+// revoked signed token: token | expiry day | state
+// login failures: username | failure days (u32 count prefixed i32s) | locked until i32, -1 if not locked
+// backend is u8 0 Rust, 1 C. Integers are little endian, strings are u16 length prefixed, lists are u32 count prefixed
+use std::fs;
+use std::io::Write;
+use std::path::{Path, PathBuf};
+
+use sha2::{Digest, Sha256};
+
+use crate::database_fix_full::{
+    byte_to_string, copy_string, DatabaseError, UserStruct, PASSWORD_HASH_LEN, PASSWORD_SALT_LEN,
+};
+use crate::lockout::LoginFailures;
+use crate::session::{RefreshToken, Session, SessionState};
+use crate::wal::Backend;
+
+const SNAPSHOT_MAGIC: &[u8; 8] = b"ESDBSNAP";
+pub const SNAPSHOT_VERSION: u32 = 1;
+const CHECKSUM_LEN: usize = 32;
+const HEADER_LEN: usize = SNAPSHOT_MAGIC.len() + 4 + 8 + CHECKSUM_LEN;
+
+pub struct Snapshot {
+    pub day: i32,
+    // last write-ahead log record the snapshot already reflects
+    pub wal_lsn: u64,
+    pub rust_users: Vec<UserStruct>,
+    // only users C is primary for, its handles to shared Rust users are rebuilt by the next join
+    pub c_users: Vec<UserStruct>,
+    pub sessions: Vec<Session>,
+    pub refresh_tokens: Vec<RefreshToken>,
+    // (token, expiry day, why it ended)
+    pub revoked_tokens: Vec<(String, i32, SessionState)>,
+    pub login_failures: Vec<LoginFailures>,
+}
+
+fn corrupt(why: &str) -> DatabaseError {
+    DatabaseError::CorruptSnapshot(why.to_string())
+}
+
+// a new file in `path`'s directory that nothing else is using, never an existing one
+fn create_temp_beside(path: &Path) -> Result<(PathBuf, fs::File), DatabaseError> {
+    let name = path.file_name().map(|name| name.to_string_lossy().into_owned()).unwrap_or_default();
+    let mut nonce = [0u8; 8];
+    loop {
+        getrandom::getrandom(&mut nonce)
+            .map_err(|e| DatabaseError::BackendFailure(format!("no randomness for a temporary file: {}", e)))?;
+        let suffix: String = nonce.iter().map(|b| format!("{:02x}", b)).collect();
+        let tmp_path = path.with_file_name(format!(".{}.{}.tmp", name, suffix));
+        match fs::OpenOptions::new().write(true).create_new(true).open(&tmp_path) {
+            Ok(file) => return Ok((tmp_path, file)),
+            Err(e) if e.kind() == std::io::ErrorKind::AlreadyExists => continue,
+            Err(e) => return Err(e.into()),
+        }
+    }
+}
+
+impl Snapshot {
+    pub fn save(&self, path: &Path) -> Result<(), DatabaseError> {
+        let bytes = self.encode()?;
+        // write beside the target and rename, a crash mid-write leaves the old snapshot intact
+        let (tmp_path, mut file) = create_temp_beside(path)?;
+        let written = file
+            .write_all(&bytes)
+            .and_then(|_| file.sync_all())
+            .and_then(|_| fs::rename(&tmp_path, path));
+        if let Err(e) = written {
+            let _ = fs::remove_file(&tmp_path);
+            return Err(e.into());
+        }
+        // the rename is only durable once the directory entry is
+        let dir = match path.parent() {
+            Some(dir) if !dir.as_os_str().is_empty() => dir,
+            _ => Path::new("."),
+        };
+        fs::File::open(dir)?.sync_all()?;
+        Ok(())
+    }
+
+    pub fn load(path: &Path) -> Result<Self, DatabaseError> {
+        Self::decode(&fs::read(path)?)
+    }
+
+    pub fn encode(&self) -> Result<Vec<u8>, DatabaseError> {
+        let mut payload = Writer::default();
+        payload.i32(self.day);
+        payload.u64(self.wal_lsn);
+        for users in [&self.rust_users, &self.c_users] {
+            payload.u32(users.len() as u32);
+            for user in users {
+                payload.user(user);
+            }
+        }
+        payload.u32(self.sessions.len() as u32);
+        for session in &self.sessions {
+            payload.session(session);
+        }
+        payload.u32(self.refresh_tokens.len() as u32);
+        for grant in &self.refresh_tokens {
+            payload.str(&grant.token);
+            payload.str(&grant.username);
+            payload.str(&grant.client);
+            payload.i32(grant.issued_day);
+        }
+        payload.u32(self.revoked_tokens.len() as u32);
+        for (token, expires_day, state) in &self.revoked_tokens {
+            payload.str(token);
+            payload.i32(*expires_day);
+            payload.state(*state);
+        }
+        payload.u32(self.login_failures.len() as u32);
+        for record in &self.login_failures {
+            payload.str(&record.username);
+            payload.u32(record.failure_days.len() as u32);
+            for day in &record.failure_days {
+                payload.i32(*day);
+            }
+            payload.i32(record.locked_until.unwrap_or(-1));
+        }
+        let payload = payload.into_bytes()?;
+
+        let mut out = Writer::default();
+        out.raw(SNAPSHOT_MAGIC);
+        out.u32(SNAPSHOT_VERSION);
+        out.u64(payload.len() as u64);
+        out.raw(&Sha256::digest(&payload));
+        out.raw(&payload);
+        out.into_bytes()
+    }
+
+    pub fn decode(bytes: &[u8]) -> Result<Self, DatabaseError> {
+        if bytes.len() < HEADER_LEN || &bytes[..SNAPSHOT_MAGIC.len()] != SNAPSHOT_MAGIC {
+            return Err(corrupt("not a snapshot file"));
+        }
+        let mut header = Reader::new(&bytes[SNAPSHOT_MAGIC.len()..HEADER_LEN]);
+        let version = header.u32()?;
+        if version != SNAPSHOT_VERSION {
+            return Err(DatabaseError::CorruptSnapshot(format!(
+                "unsupported schema version {}",
+                version
+            )));
+        }
+        let payload_len = header.u64()?;
+        let checksum = header.take(CHECKSUM_LEN)?;
+        let payload = &bytes[HEADER_LEN..];
+        if payload.len() as u64 != payload_len {
+            return Err(corrupt("payload length does not match header"));
+        }
+        if Sha256::digest(payload).as_slice() != checksum {
+            return Err(corrupt("checksum mismatch"));
+        }
+
+        let mut reader = Reader::new(payload);
+        let day = reader.i32()?;
+        let wal_lsn = reader.u64()?;
+        let rust_users = reader.users()?;
+        let c_users = reader.users()?;
+        let session_count = reader.u32()?;
+        let mut sessions = Vec::new();
+        for _ in 0..session_count {
+            sessions.push(reader.session()?);
+        }
+        let mut refresh_tokens = Vec::new();
+        for _ in 0..reader.u32()? {
+            refresh_tokens.push(RefreshToken {
+                token: reader.str()?,
+                username: reader.str()?,
+                client: reader.str()?,
+                issued_day: reader.i32()?,
+            });
+        }
+        let mut revoked_tokens = Vec::new();
+        for _ in 0..reader.u32()? {
+            revoked_tokens.push((reader.str()?, reader.i32()?, reader.state()?));
+        }
+        let mut login_failures = Vec::new();
+        for _ in 0..reader.u32()? {
+            let username = reader.str()?;
+            let mut failure_days = Vec::new();
+            for _ in 0..reader.u32()? {
+                failure_days.push(reader.i32()?);
+            }
+            let locked_until = Some(reader.i32()?).filter(|&day| day >= 0);
+            login_failures.push(LoginFailures { username, failure_days, locked_until });
+        }
+        if !reader.is_empty() {
+            return Err(corrupt("trailing bytes after payload"));
+        }
+        Ok(Snapshot {
+            day,
+            wal_lsn,
+            rust_users,
+            c_users,
+            sessions,
+            refresh_tokens,
+            revoked_tokens,
+            login_failures,
+        })
+    }
+}
+
+#[derive(Default)]
+pub struct Writer {
+    buf: Vec<u8>,
+    // the first value that couldn't be encoded, into_bytes returns it
+    error: Option<DatabaseError>,
+}
+
+impl Writer {
+    pub fn into_bytes(self) -> Result<Vec<u8>, DatabaseError> {
+        match self.error {
+            Some(e) => Err(e),
+            None => Ok(self.buf),
+        }
+    }
+
+    pub fn u8(&mut self, value: u8) {
+        self.buf.push(value);
+    }
+
+    pub fn u32(&mut self, value: u32) {
+        self.buf.extend_from_slice(&value.to_le_bytes());
+    }
+
+    pub fn u64(&mut self, value: u64) {
+        self.buf.extend_from_slice(&value.to_le_bytes());
+    }
+
+    pub fn i32(&mut self, value: i32) {
+        self.buf.extend_from_slice(&value.to_le_bytes());
+    }
+
+    pub fn raw(&mut self, bytes: &[u8]) {
+        self.buf.extend_from_slice(bytes);
+    }
+
+    pub fn str(&mut self, value: &str) {
+        // cutting it short could split a character, Reader::str would then refuse it
+        let Ok(len) = u16::try_from(value.len()) else {
+            self.error.get_or_insert_with(|| {
+                DatabaseError::InvalidInput(format!("a {} byte string is too long to save", value.len()))
+            });
+            return;
+        };
+        self.buf.extend_from_slice(&len.to_le_bytes());
+        self.buf.extend_from_slice(value.as_bytes());
+    }
+
+    pub fn user(&mut self, user: &UserStruct) {
+        self.str(&byte_to_string(&user.username));
+        self.str(&byte_to_string(&user.email));
+        self.i32(user.user_id);
+        self.i32(user.inactivity_count);
+        self.i32(user.is_active);
+        self.str(&byte_to_string(&user.session_token));
+        self.raw(&user.password_hash);
+        self.raw(&user.password_salt);
+    }
+
+    pub fn session(&mut self, session: &Session) {
+        self.i32(session.user_id);
+        self.backend(session.backend);
+        self.str(&session.username);
+        self.str(&session.token);
+        self.i32(session.idle_time);
+        self.state(session.state);
+        self.i32(session.created_day);
+        self.str(&session.client);
+    }
+
+    pub fn backend(&mut self, backend: Backend) {
+        self.u8(match backend {
+            Backend::Rust => 0,
+            Backend::C => 1,
+        });
+    }
+
+    pub fn state(&mut self, state: SessionState) {
+        self.i32(match state {
+            SessionState::Expired => 0,
+            SessionState::Active => 1,
+            SessionState::Revoked => 2,
+        });
+    }
+}
+
+pub struct Reader<'a> {
+    bytes: &'a [u8],
+    pos: usize,
+}
+
+impl<'a> Reader<'a> {
+    pub fn new(bytes: &'a [u8]) -> Self {
+        Reader { bytes, pos: 0 }
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.pos == self.bytes.len()
+    }
+
+    pub fn take(&mut self, len: usize) -> Result<&'a [u8], DatabaseError> {
+        if self.bytes.len() - self.pos < len {
+            return Err(corrupt("unexpected end of data"));
+        }
+        let taken = &self.bytes[self.pos..self.pos + len];
+        self.pos += len;
+        Ok(taken)
+    }
+
+    fn array<const N: usize>(&mut self) -> Result<[u8; N], DatabaseError> {
+        let mut out = [0u8; N];
+        out.copy_from_slice(self.take(N)?);
+        Ok(out)
+    }
+
+    pub fn u8(&mut self) -> Result<u8, DatabaseError> {
+        Ok(self.take(1)?[0])
+    }
+
+    pub fn u32(&mut self) -> Result<u32, DatabaseError> {
+        Ok(u32::from_le_bytes(self.array()?))
+    }
+
+    pub fn u64(&mut self) -> Result<u64, DatabaseError> {
+        Ok(u64::from_le_bytes(self.array()?))
+    }
+
+    pub fn i32(&mut self) -> Result<i32, DatabaseError> {
+        Ok(i32::from_le_bytes(self.array()?))
+    }
+
+    pub fn str(&mut self) -> Result<String, DatabaseError> {
+        let len = u16::from_le_bytes(self.array()?) as usize;
+        let bytes = self.take(len)?;
+        String::from_utf8(bytes.to_vec()).map_err(|_| corrupt("string is not UTF-8"))
+    }
+
+    pub fn user(&mut self) -> Result<UserStruct, DatabaseError> {
+        let mut user = UserStruct::default();
+        copy_string(&mut user.username, &self.str()?);
+        copy_string(&mut user.email, &self.str()?);
+        user.user_id = self.i32()?;
+        user.inactivity_count = self.i32()?;
+        user.is_active = self.i32()?;
+        copy_string(&mut user.session_token, &self.str()?);
+        user.password_hash = self.array::<PASSWORD_HASH_LEN>()?;
+        user.password_salt = self.array::<PASSWORD_SALT_LEN>()?;
+        Ok(user)
+    }
+
+    fn users(&mut self) -> Result<Vec<UserStruct>, DatabaseError> {
+        let count = self.u32()?;
+        let mut users = Vec::new();
+        for _ in 0..count {
+            users.push(self.user()?);
+        }
+        Ok(users)
+    }
+
+    pub fn session(&mut self) -> Result<Session, DatabaseError> {
+        let user_id = self.i32()?;
+        let backend = self.backend()?;
+        let username = self.str()?;
+        let token = self.str()?;
+        let idle_time = self.i32()?;
+        let state = self.state()?;
+        let created_day = self.i32()?;
+        let client = self.str()?;
+        let mut session = Session::new(user_id, backend, &username, &token, &client, created_day);
+        session.idle_time = idle_time;
+        session.state = state;
+        Ok(session)
+    }
+
+    pub fn backend(&mut self) -> Result<Backend, DatabaseError> {
+        match self.u8()? {
+            0 => Ok(Backend::Rust),
+            1 => Ok(Backend::C),
+            other => Err(corrupt(&format!("unknown backend {}", other))),
+        }
+    }
+
+    pub fn state(&mut self) -> Result<SessionState, DatabaseError> {
+        Ok(match self.i32()? {
+            0 => SessionState::Expired,
+            1 => SessionState::Active,
+            2 => SessionState::Revoked,
+            other => return Err(DatabaseError::CorruptSnapshot(format!("unknown session state {}", other))),
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::clock::{Clock, ManualClock};
+    use crate::database_fix_full::{create_user, OwnershipType};
+
+    // a day-3 state with one of everything the payload carries
+    fn sample() -> Snapshot {
+        let mut clock = ManualClock::default();
+        for _ in 0..3 {
+            clock.advance_day();
+        }
+        let day = clock.day();
+        let mut rust_user = *create_user("alice", "alice@example.com", 1, "secret").unwrap();
+        rust_user.inactivity_count = 2;
+        copy_string(&mut rust_user.session_token, "tok-alice");
+        let mut c_user = *create_user("bob", "bob@example.com", 7, "hunter2").unwrap();
+        c_user.is_active = 0;
+        c_user.ownership = OwnershipType::COwned as i32;
+        let mut session = Session::new(1, Backend::Rust, "alice", "tok-alice", "phone", day);
+        session.idle_time = 1;
+        let mut revoked = Session::new(7, Backend::C, "bob", "tok-bob", "web", day - 1);
+        revoked.state = SessionState::Revoked;
+        Snapshot {
+            day,
+            wal_lsn: 42,
+            rust_users: vec![rust_user],
+            c_users: vec![c_user],
+            sessions: vec![session, revoked],
+            refresh_tokens: vec![RefreshToken {
+                token: "refresh-alice".to_string(),
+                username: "alice".to_string(),
+                client: "phone".to_string(),
+                issued_day: day,
+            }],
+            revoked_tokens: vec![("signed-bob".to_string(), day + 2, SessionState::Revoked)],
+            login_failures: vec![LoginFailures {
+                username: "bob".to_string(),
+                failure_days: vec![day - 1, day],
+                locked_until: Some(day + 1),
+            }],
+        }
+    }
+
+    // what a snapshot keeps of a user, ownership is rebuilt on load
+    fn saved_fields(user: &UserStruct) -> (String, String, i32, i32, i32, String, Vec<u8>, Vec<u8>) {
+        (
+            byte_to_string(&user.username),
+            byte_to_string(&user.email),
+            user.user_id,
+            user.inactivity_count,
+            user.is_active,
+            byte_to_string(&user.session_token),
+            user.password_hash.to_vec(),
+            user.password_salt.to_vec(),
+        )
+    }
+
+    fn assert_same(a: &Snapshot, b: &Snapshot) {
+        assert_eq!(a.day, b.day);
+        assert_eq!(a.wal_lsn, b.wal_lsn);
+        for (left, right) in [(&a.rust_users, &b.rust_users), (&a.c_users, &b.c_users)] {
+            assert_eq!(
+                left.iter().map(saved_fields).collect::<Vec<_>>(),
+                right.iter().map(saved_fields).collect::<Vec<_>>()
+            );
+        }
+        assert_eq!(a.sessions, b.sessions);
+        assert_eq!(a.refresh_tokens, b.refresh_tokens);
+        assert_eq!(a.revoked_tokens, b.revoked_tokens);
+        assert_eq!(a.login_failures, b.login_failures);
+    }
+
+    #[test]
+    fn encode_decode_round_trip() {
+        let snapshot = sample();
+        let decoded = Snapshot::decode(&snapshot.encode().unwrap()).unwrap();
+        assert_same(&snapshot, &decoded);
+        assert_eq!(decoded.day, 3);
+    }
+
+    #[test]
+    fn save_load_round_trip() {
+        let path = std::env::temp_dir().join(format!("esdb-snapshot-test-{}.snap", std::process::id()));
+        let snapshot = sample();
+        snapshot.save(&path).unwrap();
+        let loaded = Snapshot::load(&path);
+        let _ = fs::remove_file(&path);
+        assert_same(&snapshot, &loaded.unwrap());
+    }
+
+    #[test]
+    fn checksum_mismatch_is_rejected() {
+        let mut bytes = sample().encode().unwrap();
+        let last = bytes.len() - 1;
+        bytes[last] ^= 0xff;
+        assert_eq!(Snapshot::decode(&bytes).err(), Some(corrupt("checksum mismatch")));
+    }
+
+    #[test]
+    fn truncated_payload_is_rejected() {
+        let bytes = sample().encode().unwrap();
+        assert_eq!(
+            Snapshot::decode(&bytes[..bytes.len() - 1]).err(),
+            Some(corrupt("payload length does not match header"))
+        );
+        assert_eq!(Snapshot::decode(&bytes[..HEADER_LEN - 1]).err(), Some(corrupt("not a snapshot file")));
+    }
+
+    #[test]
+    fn unknown_version_is_rejected() {
+        let mut bytes = sample().encode().unwrap();
+        bytes[SNAPSHOT_MAGIC.len()..SNAPSHOT_MAGIC.len() + 4].copy_from_slice(&(SNAPSHOT_VERSION + 1).to_le_bytes());
+        assert!(matches!(
+            Snapshot::decode(&bytes),
+            Err(DatabaseError::CorruptSnapshot(why)) if why.contains("unsupported schema version")
+        ));
+    }
+
+    #[test]
+    fn save_leaves_sibling_files_alone() {
+        let dir = std::env::temp_dir().join(format!("esdb-snapshot-dir-{}", std::process::id()));
+        let _ = fs::remove_dir_all(&dir);
+        fs::create_dir(&dir).unwrap();
+        let path = dir.join("state.snap");
+        // the name a fixed temporary extension would have picked
+        fs::write(dir.join("state.tmp"), b"not ours").unwrap();
+        let snapshot = sample();
+        snapshot.save(&path).unwrap();
+        snapshot.save(&path).unwrap();
+        let mut names: Vec<String> =
+            fs::read_dir(&dir).unwrap().map(|entry| entry.unwrap().file_name().to_string_lossy().into_owned()).collect();
+        names.sort();
+        let sibling = fs::read(dir.join("state.tmp")).unwrap();
+        let loaded = Snapshot::load(&path);
+        let _ = fs::remove_dir_all(&dir);
+        assert_eq!(names, ["state.snap", "state.tmp"]);
+        assert_eq!(sibling, b"not ours");
+        assert_same(&snapshot, &loaded.unwrap());
+    }
+
+    #[test]
+    fn oversized_string_is_refused() {
+        let mut snapshot = sample();
+        // a cut at u16::MAX bytes would land inside the last character
+        snapshot.sessions[0].client = format!("{}é", "c".repeat(u16::MAX as usize - 1));
+        assert!(matches!(snapshot.encode(), Err(DatabaseError::InvalidInput(_))));
+    }
+}
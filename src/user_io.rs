@@ -0,0 +1,179 @@
+// CSV and JSON import/export of user lists.
+//
+// Exports never contain credentials. Imports may carry a plaintext `password` column, rows
+// without one get a random password that the import report hands back.
+use std::io::{Read, Write};
+
+use serde::{Deserialize, Serialize};
+
+use crate::database_fix_full::{DatabaseError, MAX_EMAIL_LEN, MAX_NAME_LEN};
+use crate::wal::Backend;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum UserFormat {
+    Csv,
+    Json,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct UserRecord {
+    pub username: String,
+    pub email: String,
+    pub id: i32,
+    pub inactivity: i32,
+    pub active: bool,
+    pub backend: Backend,
+    #[serde(default, skip_serializing)]
+    pub password: Option<String>,
+}
+
+#[derive(Debug, Default)]
+pub struct ImportReport {
+    // usernames imported, or on a dry run the ones that would be
+    pub imported: Vec<String>,
+    // (row, username, reason) in row order, rows count from 1
+    pub rejected: Vec<(usize, String, DatabaseError)>,
+    // (username, password) for imported rows that had no password, stored nowhere else so
+    // they have to be handed out from here. Empty on a dry run.
+    pub generated_passwords: Vec<(String, String)>,
+}
+
+fn bad_input(format: UserFormat, e: impl std::fmt::Display) -> DatabaseError {
+    DatabaseError::InvalidInput(format!("bad {:?} user list: {}", format, e))
+}
+
+pub fn write_users<W: Write>(format: UserFormat, users: &[UserRecord], out: W) -> Result<(), DatabaseError> {
+    match format {
+        UserFormat::Csv => {
+            let mut writer = csv::Writer::from_writer(out);
+            for user in users {
+                writer.serialize(user).map_err(|e| bad_input(format, e))?;
+            }
+            writer.flush()?;
+        }
+        UserFormat::Json => {
+            serde_json::to_writer_pretty(out, users).map_err(|e| bad_input(format, e))?;
+        }
+    }
+    Ok(())
+}
+
+pub fn read_users<R: Read>(format: UserFormat, input: R) -> Result<Vec<UserRecord>, DatabaseError> {
+    match format {
+        UserFormat::Csv => csv::Reader::from_reader(input)
+            .deserialize()
+            .collect::<Result<_, _>>()
+            .map_err(|e| bad_input(format, e)),
+        UserFormat::Json => serde_json::from_reader(input).map_err(|e| bad_input(format, e)),
+    }
+}
+
+// field checks that don't need the database, nothing is silently truncated on import
+pub fn validate_record(record: &UserRecord) -> Result<(), DatabaseError> {
+    validate_username(&record.username)?;
+    validate_email(&record.email)?;
+    if record.id <= 0 {
+        return Err(DatabaseError::InvalidInput(format!("id {} is not positive", record.id)));
+    }
+    if record.inactivity < 0 {
+        return Err(DatabaseError::InvalidInput(format!(
+            "inactivity {} is negative",
+            record.inactivity
+        )));
+    }
+    Ok(())
+}
+
+/// A username both backends store whole, signups and renames are held to it too
+pub fn validate_username(username: &str) -> Result<(), DatabaseError> {
+    validate_field("username", username, MAX_NAME_LEN)
+}
+
+pub fn validate_email(email: &str) -> Result<(), DatabaseError> {
+    validate_field("email", email, MAX_EMAIL_LEN)
+}
+
+// the stored field keeps a NUL terminator, a longer value would be cut and could then
+// collide with another user's
+fn validate_field(field: &str, value: &str, max_len: usize) -> Result<(), DatabaseError> {
+    if value.is_empty() {
+        return Err(DatabaseError::InvalidInput(format!("{} is empty", field)));
+    }
+    if value.len() >= max_len {
+        return Err(DatabaseError::InvalidInput(format!(
+            "{} is longer than {} bytes",
+            field,
+            max_len - 1
+        )));
+    }
+    if value.contains('\0') {
+        return Err(DatabaseError::InvalidInput(format!("{} contains a NUL byte", field)));
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn record(username: &str, backend: Backend) -> UserRecord {
+        UserRecord {
+            username: username.to_string(),
+            email: format!("{}@example.com", username),
+            id: 7,
+            inactivity: 2,
+            active: true,
+            backend,
+            password: None,
+        }
+    }
+
+    #[test]
+    fn round_trip() {
+        let users = vec![record("alice", Backend::Rust), record("bob, jr", Backend::C)];
+        for format in [UserFormat::Csv, UserFormat::Json] {
+            let mut out = Vec::new();
+            write_users(format, &users, &mut out).unwrap();
+            assert_eq!(read_users(format, out.as_slice()).unwrap(), users, "{:?}", format);
+        }
+    }
+
+    #[test]
+    fn passwords_are_read_but_never_written() {
+        let mut user = record("alice", Backend::Rust);
+        user.password = Some("secret".to_string());
+        for format in [UserFormat::Csv, UserFormat::Json] {
+            let mut out = Vec::new();
+            write_users(format, std::slice::from_ref(&user), &mut out).unwrap();
+            assert!(!String::from_utf8(out.clone()).unwrap().contains("secret"), "{:?}", format);
+            assert_eq!(read_users(format, out.as_slice()).unwrap()[0].password, None);
+        }
+        let csv = "username,email,id,inactivity,active,backend,password\n\
+                   alice,alice@example.com,7,2,true,rust,secret\n";
+        let read = read_users(UserFormat::Csv, csv.as_bytes()).unwrap();
+        assert_eq!(read, vec![user]);
+    }
+
+    #[test]
+    fn malformed_lists_are_invalid_input() {
+        let bad_backend = "username,email,id,inactivity,active,backend\nalice,a@example.com,1,0,true,cobol\n";
+        assert!(matches!(read_users(UserFormat::Csv, bad_backend.as_bytes()), Err(DatabaseError::InvalidInput(_))));
+        assert!(matches!(read_users(UserFormat::Json, &b"[{"[..]), Err(DatabaseError::InvalidInput(_))));
+    }
+
+    #[test]
+    fn validate_record_refuses_what_would_be_stored_differently() {
+        assert_eq!(validate_record(&record("alice", Backend::Rust)), Ok(()));
+        let long = "x".repeat(MAX_NAME_LEN);
+        assert_eq!(validate_username(&"x".repeat(MAX_NAME_LEN - 1)), Ok(()));
+        for bad in [
+            UserRecord { username: String::new(), ..record("alice", Backend::Rust) },
+            UserRecord { username: long, ..record("alice", Backend::Rust) },
+            UserRecord { email: "a\0@example.com".to_string(), ..record("alice", Backend::Rust) },
+            UserRecord { id: 0, ..record("alice", Backend::Rust) },
+            UserRecord { inactivity: -1, ..record("alice", Backend::Rust) },
+        ] {
+            assert!(matches!(validate_record(&bad), Err(DatabaseError::InvalidInput(_))), "{:?}", bad);
+        }
+    }
+}
@@ -0,0 +1,329 @@
+// Write-ahead log, every state change is appended here before it is applied.
+//
+// frame: body length u32 | CRC-32 of body u32 | body
+// body:  lsn u64 | record tag u8 | record fields, encoded like snapshots
+// A frame that is short or fails its checksum is a torn append from a crash, it and
+// everything after it is cut off when the log is opened.
+use std::fs::{File, OpenOptions};
+use std::io::{Read, Write};
+use std::path::Path;
+
+use serde::{Deserialize, Serialize};
+
+use crate::database_fix_full::{DatabaseError, UserStruct};
+use crate::snapshot::{Reader, Writer};
+
+const FRAME_HEADER_LEN: usize = 8;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum Backend {
+    Rust,
+    C,
+}
+
+pub enum WalRecord {
+    // an already hashed signup with the id it was given
+    Signup { backend: Backend, user: UserStruct },
+    // a session and the token minted for it in Rust, logged before the session is installed
+    Login { username: String, token: String, client: String },
+    // day counter bump and the session idle tick
+    NewDay,
+    // Rust's daily purge of inactive users
+    Purge,
+    // every fifth day, Rust users are shared with C and C users are copied into Rust, same-name
+    // conflicts settled by the join policy
+    Join,
+    // C's daily pass, which purges and compacts (every eighth day) the C array
+    Compaction,
+    // one session ended by its holder
+    Logout { token: String },
+    // every live session of a user
+    RevokeUser { username: String },
+    // every live session created before `day`
+    RevokeBefore { day: i32 },
+    // activity on a session, which resets its idle time
+    Touch { token: String },
+    // a refresh token granted for the user and client of a live session
+    IssueRefresh { session_token: String, refresh_token: String },
+    // a refresh token exchanged for a new session and its replacement
+    Refresh { refresh_token: String, token: String, new_refresh: String },
+    // a wrong password, counted towards a lockout
+    LoginFailed { username: String },
+    // both backends brought in line, `source` winning wherever their values differ
+    Reconcile { source: Backend },
+    // a Rust user given a new username
+    Rename { old: String, new: String },
+}
+
+impl WalRecord {
+    fn encode(&self, out: &mut Writer) {
+        match self {
+            WalRecord::Signup { backend, user } => {
+                out.u8(1);
+                out.backend(*backend);
+                out.user(user);
+            }
+            WalRecord::Login { username, token, client } => {
+                out.u8(2);
+                out.str(username);
+                out.str(token);
+                out.str(client);
+            }
+            WalRecord::NewDay => out.u8(3),
+            WalRecord::Purge => out.u8(4),
+            WalRecord::Join => out.u8(5),
+            WalRecord::Compaction => out.u8(6),
+            WalRecord::Logout { token } => {
+                out.u8(7);
+                out.str(token);
+            }
+            WalRecord::RevokeUser { username } => {
+                out.u8(8);
+                out.str(username);
+            }
+            WalRecord::RevokeBefore { day } => {
+                out.u8(9);
+                out.i32(*day);
+            }
+            WalRecord::Touch { token } => {
+                out.u8(10);
+                out.str(token);
+            }
+            WalRecord::IssueRefresh { session_token, refresh_token } => {
+                out.u8(11);
+                out.str(session_token);
+                out.str(refresh_token);
+            }
+            WalRecord::Refresh { refresh_token, token, new_refresh } => {
+                out.u8(12);
+                out.str(refresh_token);
+                out.str(token);
+                out.str(new_refresh);
+            }
+            WalRecord::LoginFailed { username } => {
+                out.u8(13);
+                out.str(username);
+            }
+            WalRecord::Reconcile { source } => {
+                out.u8(14);
+                out.backend(*source);
+            }
+            WalRecord::Rename { old, new } => {
+                out.u8(15);
+                out.str(old);
+                out.str(new);
+            }
+        }
+    }
+
+    fn decode(reader: &mut Reader) -> Result<Self, DatabaseError> {
+        Ok(match reader.u8()? {
+            1 => WalRecord::Signup { backend: reader.backend()?, user: reader.user()? },
+            2 => WalRecord::Login { username: reader.str()?, token: reader.str()?, client: reader.str()? },
+            3 => WalRecord::NewDay,
+            4 => WalRecord::Purge,
+            5 => WalRecord::Join,
+            6 => WalRecord::Compaction,
+            7 => WalRecord::Logout { token: reader.str()? },
+            8 => WalRecord::RevokeUser { username: reader.str()? },
+            9 => WalRecord::RevokeBefore { day: reader.i32()? },
+            10 => WalRecord::Touch { token: reader.str()? },
+            11 => WalRecord::IssueRefresh { session_token: reader.str()?, refresh_token: reader.str()? },
+            12 => WalRecord::Refresh { refresh_token: reader.str()?, token: reader.str()?, new_refresh: reader.str()? },
+            13 => WalRecord::LoginFailed { username: reader.str()? },
+            14 => WalRecord::Reconcile { source: reader.backend()? },
+            15 => WalRecord::Rename { old: reader.str()?, new: reader.str()? },
+            other => return Err(DatabaseError::CorruptSnapshot(format!("unknown WAL record {}", other))),
+        })
+    }
+}
+
+pub struct Wal {
+    file: File,
+    // bytes of intact records, a failed append is cut back to this
+    len: u64,
+}
+
+impl Wal {
+    /// Open (creating it if needed) the log at `path` and return its intact records in order,
+    /// truncating a torn tail left by a crash mid-append
+    pub fn open(path: &Path) -> Result<(Self, Vec<(u64, WalRecord)>), DatabaseError> {
+        let mut file = OpenOptions::new().read(true).append(true).create(true).open(path)?;
+        let mut bytes = Vec::new();
+        file.read_to_end(&mut bytes)?;
+
+        let mut records = Vec::new();
+        let mut pos = 0;
+        while let Some((lsn, record, frame_len)) = decode_frame(&bytes[pos..]) {
+            records.push((lsn, record));
+            pos += frame_len;
+        }
+        if pos < bytes.len() {
+            println!("[WAL] Dropping {} bytes of torn records", bytes.len() - pos);
+            file.set_len(pos as u64)?;
+            file.sync_all()?;
+        }
+        Ok((Wal { file, len: pos as u64 }, records))
+    }
+
+    /// Durably append `record`, it is on disk once this returns Ok
+    pub fn append(&mut self, lsn: u64, record: &WalRecord) -> Result<(), DatabaseError> {
+        let mut body = Writer::default();
+        body.u64(lsn);
+        record.encode(&mut body);
+        let body = body.into_bytes()?;
+
+        let mut frame = Writer::default();
+        frame.u32(body.len() as u32);
+        frame.u32(crc32fast::hash(&body));
+        frame.raw(&body);
+        let frame = frame.into_bytes()?;
+
+        if let Err(e) = self.file.write_all(&frame).and_then(|_| self.file.sync_data()) {
+            // don't leave half a frame for the next append to land behind
+            let _ = self.file.set_len(self.len);
+            return Err(e.into());
+        }
+        self.len += frame.len() as u64;
+        Ok(())
+    }
+
+    /// Drop every record, once a snapshot covers them
+    pub fn truncate(&mut self) -> Result<(), DatabaseError> {
+        self.file.set_len(0)?;
+        self.file.sync_all()?;
+        self.len = 0;
+        Ok(())
+    }
+}
+
+fn decode_frame(bytes: &[u8]) -> Option<(u64, WalRecord, usize)> {
+    let mut header = Reader::new(bytes);
+    let body_len = header.u32().ok()? as usize;
+    let checksum = header.u32().ok()?;
+    let body = header.take(body_len).ok()?;
+    if crc32fast::hash(body) != checksum {
+        return None;
+    }
+    let mut reader = Reader::new(body);
+    let lsn = reader.u64().ok()?;
+    let record = WalRecord::decode(&mut reader).ok()?;
+    if !reader.is_empty() {
+        return None;
+    }
+    Some((lsn, record, FRAME_HEADER_LEN + body_len))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::database_fix_full::create_user;
+
+    fn temp_log(name: &str) -> std::path::PathBuf {
+        let path = std::env::temp_dir().join(format!("esdb-wal-test-{}-{}.wal", name, std::process::id()));
+        let _ = std::fs::remove_file(&path);
+        path
+    }
+
+    fn encoded(record: &WalRecord) -> Vec<u8> {
+        let mut out = Writer::default();
+        record.encode(&mut out);
+        out.into_bytes().unwrap()
+    }
+
+    fn sample() -> Vec<WalRecord> {
+        vec![
+            WalRecord::Signup { backend: Backend::C, user: *create_user("alice", "alice@example.com", 3, "secret").unwrap() },
+            WalRecord::Login { username: "alice".to_string(), token: "tok".to_string(), client: "phone".to_string() },
+            WalRecord::NewDay,
+            WalRecord::Join,
+            WalRecord::RevokeBefore { day: 2 },
+            WalRecord::Refresh { refresh_token: "r1".to_string(), token: "tok2".to_string(), new_refresh: "r2".to_string() },
+            WalRecord::Reconcile { source: Backend::Rust },
+            WalRecord::Rename { old: "alice".to_string(), new: "alicia".to_string() },
+        ]
+    }
+
+    fn write_log(path: &Path, records: &[WalRecord]) {
+        let (mut wal, existing) = Wal::open(path).unwrap();
+        assert!(existing.is_empty());
+        for (i, record) in records.iter().enumerate() {
+            wal.append(i as u64 + 1, record).unwrap();
+        }
+    }
+
+    fn assert_replays(replayed: &[(u64, WalRecord)], expected: &[WalRecord]) {
+        assert_eq!(replayed.len(), expected.len());
+        for (i, ((lsn, record), expected)) in replayed.iter().zip(expected).enumerate() {
+            assert_eq!(*lsn, i as u64 + 1);
+            assert_eq!(encoded(record), encoded(expected));
+        }
+    }
+
+    #[test]
+    fn records_replay_in_order() {
+        let path = temp_log("replay");
+        let records = sample();
+        write_log(&path, &records);
+        let (_, replayed) = Wal::open(&path).unwrap();
+        let _ = std::fs::remove_file(&path);
+        assert_replays(&replayed, &records);
+    }
+
+    #[test]
+    fn torn_tail_is_cut_off() {
+        let path = temp_log("torn");
+        let records = sample();
+        write_log(&path, &records);
+        let full_len = std::fs::metadata(&path).unwrap().len();
+        // a crash halfway through the last append
+        OpenOptions::new().write(true).open(&path).unwrap().set_len(full_len - 3).unwrap();
+
+        let (mut wal, replayed) = Wal::open(&path).unwrap();
+        assert_replays(&replayed, &records[..records.len() - 1]);
+        // the torn bytes are gone, so the next append lands right after the last intact record
+        wal.append(99, &WalRecord::Purge).unwrap();
+        drop(wal);
+        let (_, replayed) = Wal::open(&path).unwrap();
+        let _ = std::fs::remove_file(&path);
+        assert_eq!(replayed.len(), records.len());
+        assert_eq!(replayed.last().map(|(lsn, _)| *lsn), Some(99));
+    }
+
+    #[test]
+    fn crc_mismatch_drops_the_record_and_the_rest() {
+        let path = temp_log("crc");
+        let records = sample();
+        write_log(&path, &records[..1]);
+        let first_len = std::fs::metadata(&path).unwrap().len();
+        let (mut wal, _) = Wal::open(&path).unwrap();
+        for (i, record) in records.iter().enumerate().skip(1) {
+            wal.append(i as u64 + 1, record).unwrap();
+        }
+        drop(wal);
+        // flip the last byte of the second record's body
+        let mut bytes = std::fs::read(&path).unwrap();
+        let second_len = FRAME_HEADER_LEN as u64 + u32::from_le_bytes(bytes[first_len as usize..first_len as usize + 4].try_into().unwrap()) as u64;
+        bytes[(first_len + second_len - 1) as usize] ^= 0xff;
+        std::fs::write(&path, &bytes).unwrap();
+
+        let (_, replayed) = Wal::open(&path).unwrap();
+        let truncated_len = std::fs::metadata(&path).unwrap().len();
+        let _ = std::fs::remove_file(&path);
+        assert_replays(&replayed, &records[..1]);
+        assert_eq!(truncated_len, first_len);
+    }
+
+    #[test]
+    fn truncate_empties_the_log() {
+        let path = temp_log("truncate");
+        write_log(&path, &sample());
+        let (mut wal, _) = Wal::open(&path).unwrap();
+        wal.truncate().unwrap();
+        drop(wal);
+        let (_, replayed) = Wal::open(&path).unwrap();
+        let _ = std::fs::remove_file(&path);
+        assert!(replayed.is_empty());
+    }
+}